@@ -0,0 +1,200 @@
+#!/usr/bin/env python3
+"""
+Stress test for concurrent cache mutation.
+
+Many threads store, retrieve and remove entries at the same time, sharing
+content between files so that blocks are deduplicated across threads. At the
+end every surviving entry is checked byte-for-byte and the block index is
+checked for leaked or missing references.
+"""
+
+import random
+import sys
+import tempfile
+import threading
+from pathlib import Path
+from unicache import Cache
+
+NUM_THREADS = 16
+OPS_PER_THREAD = 200
+BLOCK_SIZE = 4096
+
+
+def make_payloads(temp_dir, count):
+    """Create source files built from a small pool of shared blocks."""
+    pool = [random.randbytes(BLOCK_SIZE) for _ in range(32)]
+    payloads = []
+    for i in range(count):
+        data = b"".join(random.choice(pool) for _ in range(random.randint(1, 16)))
+        # Ragged tail so not every file ends on a block boundary
+        data += random.randbytes(random.randint(0, BLOCK_SIZE - 1))
+        path = temp_dir / f"src_{i}.bin"
+        path.write_bytes(data)
+        payloads.append((path, data))
+    return payloads
+
+
+def worker(cache, thread_id, payloads, out_dir, expected, id_locks, errors):
+    rng = random.Random(thread_id)
+    # Each thread owns a few ids and also touches ids shared by all threads
+    own_ids = [f"t{thread_id}_{i}" for i in range(8)]
+    shared_ids = [f"shared_{i}" for i in range(8)]
+
+    for op in range(OPS_PER_THREAD):
+        file_id = rng.choice(own_ids if rng.random() < 0.7 else shared_ids)
+        action = rng.random()
+        try:
+            if action < 0.45:
+                path, data = rng.choice(payloads)
+                with id_locks[file_id]:
+                    cache.store_file(str(path), file_id)
+                    expected[file_id] = data
+            elif action < 0.8:
+                out_path = out_dir / f"out_{thread_id}_{op}.bin"
+                with id_locks[file_id]:
+                    data = expected.get(file_id)
+                    if data is None:
+                        continue
+                    cache.retrieve_file(file_id, str(out_path))
+                if out_path.read_bytes() != data:
+                    errors.append(f"content mismatch for {file_id}")
+                out_path.unlink()
+            else:
+                with id_locks[file_id]:
+                    if file_id not in expected:
+                        continue
+                    cache.remove_file(file_id)
+                    del expected[file_id]
+        except Exception as e:
+            errors.append(f"thread {thread_id} op {op} on {file_id}: {e}")
+
+
+def unlocked_worker(cache, thread_id, payloads, errors):
+    """Hammer the cache with no external coordination at all."""
+    rng = random.Random(1000 + thread_id)
+    file_id = f"free_{thread_id}"
+    for _ in range(OPS_PER_THREAD):
+        path, _ = rng.choice(payloads)
+        try:
+            cache.store_file(str(path), file_id)
+            if rng.random() < 0.5:
+                cache.remove_file(file_id)
+        except Exception as e:
+            errors.append(f"unlocked thread {thread_id}: {e}")
+    try:
+        cache.remove_file(file_id)
+    except Exception:
+        pass
+
+
+def check_identical_ingest(temp_dir, errors):
+    """Store the same new content from many threads at once."""
+    cache_dir = temp_dir / "identical_cache"
+    cache = Cache(block_size=BLOCK_SIZE, cache_dir=str(cache_dir))
+
+    data = random.randbytes(BLOCK_SIZE * 64)
+    path = temp_dir / "identical.bin"
+    path.write_bytes(data)
+
+    barrier = threading.Barrier(NUM_THREADS)
+
+    def store(i):
+        barrier.wait()
+        try:
+            cache.store_file(str(path), f"same_{i}")
+        except Exception as e:
+            errors.append(f"identical store {i}: {e}")
+
+    threads = [threading.Thread(target=store, args=(i,)) for i in range(NUM_THREADS)]
+    for t in threads:
+        t.start()
+    for t in threads:
+        t.join()
+
+    # Each block must have been written exactly once
+    blocks, files, stored_size, _ = cache.get_stats()
+    blocks_file_size = (cache_dir / "blocks.bin").stat().st_size
+    if (blocks, files, stored_size, blocks_file_size) != (64, NUM_THREADS, len(data), len(data)):
+        errors.append(
+            f"identical ingest duplicated work: blocks={blocks} files={files} "
+            f"stored={stored_size} blocks.bin={blocks_file_size}"
+        )
+
+    # And every reference must be accounted for
+    for i in range(NUM_THREADS):
+        cache.remove_file(f"same_{i}")
+    if cache.get_stats() != (0, 0, 0, 0):
+        errors.append(f"identical ingest leaked references: {cache.get_stats()}")
+
+
+def main():
+    with tempfile.TemporaryDirectory() as temp_dir:
+        temp_dir = Path(temp_dir)
+        out_dir = temp_dir / "out"
+        out_dir.mkdir()
+        cache_dir = temp_dir / "cache"
+
+        payloads = make_payloads(temp_dir, 24)
+        cache = Cache(block_size=BLOCK_SIZE, cache_dir=str(cache_dir))
+
+        expected = {}
+        errors = []
+
+        # Per-id locks only keep `expected` in step with the cache for each id;
+        # operations on different ids still hit the cache concurrently
+        all_ids = [f"t{t}_{i}" for t in range(NUM_THREADS) for i in range(8)]
+        all_ids += [f"shared_{i}" for i in range(8)]
+        id_locks = {file_id: threading.Lock() for file_id in all_ids}
+
+        threads = [
+            threading.Thread(
+                target=worker,
+                args=(cache, i, payloads, out_dir, expected, id_locks, errors),
+            )
+            for i in range(NUM_THREADS)
+        ] + [
+            threading.Thread(target=unlocked_worker, args=(cache, i, payloads, errors))
+            for i in range(NUM_THREADS)
+        ]
+
+        print(f"Running {len(threads)} threads x {OPS_PER_THREAD} operations...")
+        for t in threads:
+            t.start()
+        for t in threads:
+            t.join()
+
+        # Every surviving entry must round-trip exactly
+        for file_id, data in expected.items():
+            out_path = out_dir / f"final_{file_id}.bin"
+            cache.retrieve_file(file_id, str(out_path))
+            if out_path.read_bytes() != data:
+                errors.append(f"final content mismatch for {file_id}")
+
+        # Removing everything must drop every block reference
+        for file_id in list(expected):
+            cache.remove_file(file_id)
+        blocks, files, stored_size, logical_size = cache.get_stats()
+        if (blocks, files, stored_size, logical_size) != (0, 0, 0, 0):
+            errors.append(
+                f"leaked state after removing all files: blocks={blocks} files={files} "
+                f"stored={stored_size} logical={logical_size}"
+            )
+
+        check_identical_ingest(temp_dir, errors)
+
+        # The persisted index must reload cleanly
+        reopened = Cache(block_size=BLOCK_SIZE, cache_dir=str(cache_dir))
+        if reopened.get_stats()[:2] != (0, 0):
+            errors.append("persisted index does not match in-memory state")
+
+        if errors:
+            print(f"FAILED with {len(errors)} errors:")
+            for error in errors[:20]:
+                print(f"  {error}")
+            sys.exit(1)
+
+        print("OK: all operations consistent")
+
+
+if __name__ == "__main__":
+    main()
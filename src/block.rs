@@ -1,11 +1,16 @@
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::io;
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use blake3::Hasher;
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
 
+use crate::deadline::Deadline;
+
 pub type BlockHash = [u8; 32];
 
 #[derive(Error, Debug)]
@@ -16,56 +21,268 @@ pub enum BlockError {
     #[error("Block not found: {0}")]
     BlockNotFound(String),
     
+    #[error("Timed out {0}")]
+    Timeout(&'static str),
+    
     #[error("Block error: {0}")]
     Other(String),
 }
 
 pub type Result<T> = std::result::Result<T, BlockError>;
 
+/// Compression applied to a block's bytes in the blocks file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+}
+
+impl Compression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockInfo {
     pub offset: u64,
     pub size: u32,
     pub ref_count: u32,
+    #[serde(default)]
+    pub compression: Compression,
+    /// Bytes occupied in the blocks file; `None` when equal to `size`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_size: Option<u32>,
+    /// Pack blocks hold several small files back to back. They are keyed by a
+    /// generated id rather than their content hash, and `ref_count` counts
+    /// member references.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pack: bool,
 }
 
-pub struct BlockStore {
-    blocks_path: PathBuf,
-    blocks_file: File,
+/// Location of a small file's content inside a pack block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackedExtent {
+    #[serde(with = "hex_hash")]
+    pub pack: BlockHash,
+    pub offset: u32,
+    pub len: u32,
+    /// Content hash of the member, used to deduplicate identical small files.
+    #[serde(with = "hex_hash")]
+    pub hash: BlockHash,
+}
+
+/// Serialize a `BlockHash` as a hex string to keep per-file index entries small.
+mod hex_hash {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use super::BlockHash;
+    
+    pub fn serialize<S: Serializer>(hash: &BlockHash, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(hash))
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BlockHash, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let bytes = hex::decode(&s).map_err(D::Error::custom)?;
+        bytes.try_into().map_err(|_| D::Error::custom("block hash must be 32 bytes"))
+    }
+}
+
+struct PackMember {
+    extent: PackedExtent,
+    refs: u32,
+}
+
+impl BlockInfo {
+    pub fn stored_size(&self) -> u32 {
+        self.stored_size.unwrap_or(self.size)
+    }
+}
+
+/// Raw vs stored byte totals for one compression algorithm.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompressionTotals {
+    pub blocks: usize,
+    pub raw_bytes: u64,
+    pub stored_bytes: u64,
+}
+
+impl CompressionTotals {
+    pub fn add(&mut self, info: &BlockInfo) {
+        self.blocks += 1;
+        self.raw_bytes += info.size as u64;
+        self.stored_bytes += info.stored_size() as u64;
+    }
+}
+
+/// Result of a compaction pass over the blocks file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompactionOutcome {
+    pub blocks_moved: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub cancelled: bool,
+}
+
+struct BlockState {
+    /// Swapped out wholesale by compaction; readers hold their own handle.
+    blocks_file: Arc<File>,
+    /// Generation of `blocks_file`, bumped by every compaction.
+    generation: u64,
     block_index: HashMap<BlockHash, BlockInfo>,
+    /// New blocks whose space is reserved but whose data is still being written.
+    in_flight: HashSet<BlockHash>,
+    /// Pack currently accepting new members; only valid while it ends at `end_offset`.
+    open_pack: Option<BlockHash>,
+    /// Packed small files by content hash. Rebuilt from the file index on load.
+    pack_members: HashMap<BlockHash, PackMember>,
+    /// Bytes of live packs that no member references any more, by pack.
+    /// Not counted as live; compaction drops them.
+    pack_dead: HashMap<BlockHash, u32>,
+    /// Content hashes of blocks and packed members that failed verification.
+    /// Storing the same content again rewrites the bytes in place.
+    corrupt: HashSet<BlockHash>,
+    /// While compaction copies, content hashes rewritten in place, which it
+    /// copies again before switching files.
+    rewritten: Option<HashSet<BlockHash>>,
+    end_offset: u64,
     modified: bool,
 }
 
+impl BlockState {
+    /// Record that the content with this hash was rewritten in place.
+    fn repaired(&mut self, hash: BlockHash) {
+        self.corrupt.remove(&hash);
+        if let Some(rewritten) = &mut self.rewritten {
+            rewritten.insert(hash);
+        }
+    }
+}
+
+/// Sizes kept outside the state lock, so status queries don't wait behind a
+/// compaction holding it. Only changed while the state lock is held.
+#[derive(Default)]
+struct Usage {
+    /// Mirrors `BlockState::end_offset`.
+    file_bytes: AtomicU64,
+    /// Stored bytes of the blocks in the index and of the packed members still referenced.
+    live_bytes: AtomicU64,
+    blocks: AtomicUsize,
+}
+
+impl Usage {
+    fn add_block(&self, stored_size: u64) {
+        self.live_bytes.fetch_add(stored_size, Ordering::Relaxed);
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    fn remove_block(&self, stored_size: u64) {
+        self.live_bytes.fetch_sub(stored_size, Ordering::Relaxed);
+        self.blocks.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Append-only block storage that is safe to share between threads.
+///
+/// Block data is read and written with positional I/O, so the internal mutex
+/// is only held while the index is consulted or space for a new block is
+/// reserved. Concurrent stores of the same new block coordinate through the
+/// in-flight set: one thread writes it, the others wait and take a reference.
+pub struct BlockStore {
+    dir: PathBuf,
+    state: Mutex<BlockState>,
+    in_flight_done: Condvar,
+    usage: Usage,
+}
+
 impl BlockStore {
-    pub fn new(blocks_path: &Path) -> Result<Self> {
-        let parent_dir = blocks_path.parent().ok_or_else(|| 
-            BlockError::Other("Invalid blocks path".to_string()))?;
-        fs::create_dir_all(parent_dir)?;
+    /// Open the blocks file of the given generation in `dir`.
+    ///
+    /// Blocks files of other generations are left over from a compaction that
+    /// was interrupted before or after the index switched over, and are removed.
+    pub fn new(dir: &Path, generation: u64) -> Result<Self> {
+        fs::create_dir_all(dir)?;
         
+        let blocks_name = blocks_file_name(generation);
         let blocks_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(blocks_path)?;
-            
+            .truncate(false)
+            .open(dir.join(&blocks_name))?;
+        let end_offset = blocks_file.metadata()?.len();
+        
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if name != blocks_name && is_blocks_file_name(&name) {
+                let _ = fs::remove_file(dir.join(&*name));
+            }
+        }
+        
         Ok(BlockStore {
-            blocks_path: blocks_path.to_path_buf(),
-            blocks_file,
-            block_index: HashMap::new(),
-            modified: false,
+            dir: dir.to_path_buf(),
+            state: Mutex::new(BlockState {
+                blocks_file: Arc::new(blocks_file),
+                generation,
+                block_index: HashMap::new(),
+                in_flight: HashSet::new(),
+                open_pack: None,
+                pack_members: HashMap::new(),
+                pack_dead: HashMap::new(),
+                corrupt: HashSet::new(),
+                rewritten: None,
+                end_offset,
+                modified: false,
+            }),
+            in_flight_done: Condvar::new(),
+            usage: Usage {
+                file_bytes: AtomicU64::new(end_offset),
+                ..Default::default()
+            },
         })
     }
     
-    pub fn set_index(&mut self, block_index: HashMap<BlockHash, BlockInfo>) {
-        self.block_index = block_index;
+    fn state(&self) -> MutexGuard<'_, BlockState> {
+        self.state.lock().unwrap()
+    }
+    
+    /// Like `state()`, but gives up once `deadline` passes (e.g. during compaction).
+    fn state_until(&self, deadline: Deadline) -> Result<MutexGuard<'_, BlockState>> {
+        deadline.lock(&self.state)
+            .ok_or(BlockError::Timeout("waiting for the block store"))
+    }
+    
+    pub fn set_index(&self, block_index: HashMap<BlockHash, BlockInfo>) {
+        let mut state = self.state();
+        let live_bytes = block_index.values().map(|info| info.stored_size() as u64).sum();
+        self.usage.live_bytes.store(live_bytes, Ordering::Relaxed);
+        self.usage.blocks.store(block_index.len(), Ordering::Relaxed);
+        state.block_index = block_index;
+    }
+    
+    /// Snapshot of the block index.
+    pub fn get_index(&self) -> HashMap<BlockHash, BlockInfo> {
+        self.state().block_index.clone()
     }
     
-    pub fn get_index(&self) -> &HashMap<BlockHash, BlockInfo> {
-        &self.block_index
+    /// Snapshot of the block index for serialization, together with the
+    /// generation of the blocks file its offsets refer to.
+    pub fn index_snapshot(&self) -> (u64, HashMap<BlockHash, BlockInfo>) {
+        let state = self.state();
+        (state.generation, state.block_index.clone())
+    }
+    
+    /// Path of the blocks file of the given generation.
+    pub fn blocks_path(&self, generation: u64) -> PathBuf {
+        self.dir.join(blocks_file_name(generation))
     }
     
     pub fn is_modified(&self) -> bool {
-        self.modified
+        self.state().modified
     }
     
     pub fn hash_block(data: &[u8]) -> BlockHash {
@@ -74,67 +291,904 @@ impl BlockStore {
         *hasher.finalize().as_bytes()
     }
     
-    pub fn store_block(&mut self, data: &[u8]) -> Result<BlockHash> {
+    /// Store a block, or take a reference to identical content already stored.
+    ///
+    /// Also returns whether this call wrote the data (a new block, or a fresh
+    /// copy of a corrupt one) rather than only taking a reference.
+    pub fn store_block(&self, data: &[u8], deadline: Deadline) -> Result<(BlockHash, bool)> {
+        // Hash outside the lock so concurrent writers only serialize on the append
         let hash = Self::hash_block(data);
         
-        if let Some(block_info) = self.block_index.get_mut(&hash) {
+        let mut state = self.state_until(deadline)?;
+        
+        // Another thread is writing this block; wait for it to land, then
+        // re-check (the writer may also have failed, making us the writer)
+        while state.in_flight.contains(&hash) {
+            state = deadline.wait(&self.in_flight_done, state)
+                .ok_or(BlockError::Timeout("waiting for a concurrent write of the same block"))?;
+        }
+        
+        let st = &mut *state;
+        if let Some(block_info) = st.block_index.get_mut(&hash) {
             // Block already exists, just increment reference count
             block_info.ref_count += 1;
-            self.modified = true;
-            return Ok(hash);
+            st.modified = true;
+            
+            if !st.corrupt.contains(&hash) {
+                return Ok((hash, false));
+            }
+            
+            // Fresh copy of a block that failed verification; same hash, same length
+            let offset = block_info.offset;
+            let (mut state, write_result) = self.write_unlocked(state, hash, data, offset);
+            if let Err(e) = write_result {
+                self.decrement_ref(&mut state, &hash)?;
+                return Err(e.into());
+            }
+            state.repaired(hash);
+            return Ok((hash, true));
         }
         
-        // New block, append to blocks file
-        let offset = self.blocks_file.seek(SeekFrom::End(0))?;
-        self.blocks_file.write_all(data)?;
+        // New block, reserve space at the end of the blocks file
+        let offset = self.append(&mut state, data.len());
+        let (mut state, write_result) = self.write_unlocked(state, hash, data, offset);
+        
+        // On failure the reserved range is left as dead space
+        write_result?;
         
         // Store block info
         let block_info = BlockInfo {
             offset,
             size: data.len() as u32,
             ref_count: 1,
+            compression: Compression::None,
+            stored_size: None,
+            pack: false,
         };
         
-        self.block_index.insert(hash, block_info);
-        self.modified = true;
+        state.block_index.insert(hash, block_info);
+        self.usage.add_block(data.len() as u64);
+        state.modified = true;
         
-        Ok(hash)
+        Ok((hash, true))
     }
     
-    pub fn read_block(&mut self, hash: &BlockHash) -> Result<Vec<u8>> {
-        let block_info = self.block_index.get(hash)
-            .ok_or_else(|| BlockError::BlockNotFound(hex::encode(hash)))?;
-            
-        let mut buffer = vec![0u8; block_info.size as usize];
-        self.blocks_file.seek(SeekFrom::Start(block_info.offset))?;
-        self.blocks_file.read_exact(&mut buffer)?;
+    /// Write `data` at `offset` with the state lock released, and take the
+    /// lock back.
+    ///
+    /// `hash` is in flight meanwhile, so stores of the same content wait for
+    /// the write and compaction doesn't start until it lands. Waiters are
+    /// woken once the returned guard is dropped.
+    fn write_unlocked<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, BlockState>,
+        hash: BlockHash,
+        data: &[u8],
+        offset: u64,
+    ) -> (MutexGuard<'a, BlockState>, io::Result<()>) {
+        state.in_flight.insert(hash);
+        let blocks_file = state.blocks_file.clone();
+        drop(state);
+        
+        let write_result = write_all_at(&blocks_file, data, offset);
+        
+        let mut state = self.state();
+        state.in_flight.remove(&hash);
+        self.in_flight_done.notify_all();
+        (state, write_result)
+    }
+    
+    /// Reserve `len` bytes at the end of the blocks file; returns their offset.
+    fn append(&self, state: &mut BlockState, len: usize) -> u64 {
+        let offset = state.end_offset;
+        state.end_offset += len as u64;
+        self.usage.file_bytes.store(state.end_offset, Ordering::Relaxed);
+        offset
+    }
+    
+    pub fn read_block(&self, hash: &BlockHash, deadline: Deadline) -> Result<Vec<u8>> {
+        let (blocks_file, block_info) = {
+            let state = self.state_until(deadline)?;
+            let block_info = state.block_index.get(hash)
+                .cloned()
+                .ok_or_else(|| BlockError::BlockNotFound(hex::encode(hash)))?;
+            (state.blocks_file.clone(), block_info)
+        };
+        
+        let mut buffer = vec![0u8; block_info.stored_size() as usize];
+        read_exact_at(&blocks_file, &mut buffer, block_info.offset)?;
         
         Ok(buffer)
     }
     
-    pub fn decrement_ref(&mut self, hash: &BlockHash) -> Result<bool> {
-        let should_remove = if let Some(block_info) = self.block_index.get_mut(hash) {
+    /// Drop one reference to each of a file's blocks and its packed extent.
+    ///
+    /// Everything is released under a single acquisition of the lock, so a
+    /// timeout leaves every reference in place.
+    pub fn release(&self, blocks: &[BlockHash], packed: Option<&PackedExtent>, deadline: Deadline) -> Result<()> {
+        let mut state = self.state_until(deadline)?;
+        for hash in blocks {
+            self.decrement_ref(&mut state, hash)?;
+        }
+        if let Some(extent) = packed {
+            self.release_packed(&mut state, extent)?;
+        }
+        Ok(())
+    }
+    
+    fn decrement_ref(&self, state: &mut BlockState, hash: &BlockHash) -> Result<bool> {
+        let should_remove = if let Some(block_info) = state.block_index.get_mut(hash) {
             block_info.ref_count -= 1;
-            self.modified = true;
             block_info.ref_count == 0
         } else {
             return Err(BlockError::BlockNotFound(hex::encode(hash)));
         };
+        state.modified = true;
         
         if should_remove {
-            self.block_index.remove(hash);
+            let info = state.block_index.remove(hash).unwrap();
+            self.usage.remove_block(info.stored_size() as u64);
         }
         
         Ok(should_remove)
     }
     
     pub fn total_size(&self) -> u64 {
-        self.block_index.values()
-            .map(|info| info.size as u64)
-            .sum()
+        self.usage.live_bytes.load(Ordering::Relaxed)
+    }
+    
+    pub fn block_info(&self, hash: &BlockHash) -> Option<BlockInfo> {
+        self.state().block_index.get(hash).cloned()
+    }
+    
+    pub fn compression_totals(&self) -> HashMap<Compression, CompressionTotals> {
+        let mut totals: HashMap<Compression, CompressionTotals> = HashMap::new();
+        for info in self.state().block_index.values() {
+            totals.entry(info.compression).or_default().add(info);
+        }
+        totals
     }
     
     pub fn block_count(&self) -> usize {
-        self.block_index.len()
+        self.usage.blocks.load(Ordering::Relaxed)
+    }
+    
+    /// Register the packed extents referenced by files loaded from the index.
+    ///
+    /// Pack bytes not covered by any of them belong to members removed
+    /// earlier and are counted as dead.
+    pub fn restore_pack_members<'a>(&self, extents: impl IntoIterator<Item = &'a PackedExtent>) {
+        let mut state = self.state();
+        let state = &mut *state;
+        
+        for extent in extents {
+            state.pack_members.entry(extent.hash)
+                .or_insert(PackMember { extent: *extent, refs: 0 })
+                .refs += 1;
+        }
+        
+        let mut member_bytes: HashMap<BlockHash, u32> = HashMap::new();
+        for member in state.pack_members.values() {
+            *member_bytes.entry(member.extent.pack).or_default() += member.extent.len;
+        }
+        
+        for (pack, info) in state.block_index.iter().filter(|(_, info)| info.pack) {
+            let dead = info.stored_size().saturating_sub(member_bytes.get(pack).copied().unwrap_or(0));
+            if dead > 0 {
+                state.pack_dead.insert(*pack, dead);
+                self.usage.live_bytes.fetch_sub(dead as u64, Ordering::Relaxed);
+            }
+        }
+    }
+    
+    /// Store a small file's content inside a shared pack block.
+    ///
+    /// Identical content already packed is referenced rather than written
+    /// again. Otherwise space is reserved at the end of the open pack if it
+    /// still sits at the end of the blocks file and has room under
+    /// `pack_limit`, or in a new pack, and the data is written there after the
+    /// lock is released. Like `store_block`, also returns whether this call
+    /// wrote the data.
+    pub fn store_packed(&self, data: &[u8], pack_limit: usize, deadline: Deadline) -> Result<(PackedExtent, bool)> {
+        let hash = Self::hash_block(data);
+        
+        let mut state = self.state_until(deadline)?;
+        
+        // Same content being written by another thread; wait for it to land
+        while state.in_flight.contains(&hash) {
+            state = deadline.wait(&self.in_flight_done, state)
+                .ok_or(BlockError::Timeout("waiting for a concurrent write of the same block"))?;
+        }
+        
+        let st = &mut *state;
+        
+        if let Some(member) = st.pack_members.get_mut(&hash) {
+            if let Some(pack_info) = st.block_index.get_mut(&member.extent.pack) {
+                member.refs += 1;
+                pack_info.ref_count += 1;
+                st.modified = true;
+                
+                let extent = member.extent;
+                if !st.corrupt.contains(&hash) {
+                    return Ok((extent, false));
+                }
+                
+                let offset = pack_info.offset + extent.offset as u64;
+                let (mut state, write_result) = self.write_unlocked(state, hash, data, offset);
+                if let Err(e) = write_result {
+                    self.release_packed(&mut state, &extent)?;
+                    return Err(e.into());
+                }
+                state.repaired(hash);
+                return Ok((extent, true));
+            }
+        }
+        
+        let open_pack = st.open_pack.filter(|pack| {
+            st.block_index.get(pack).is_some_and(|info| {
+                info.offset + info.size as u64 == st.end_offset
+                    && info.size as usize + data.len() <= pack_limit
+            })
+        });
+        
+        // Grow the pack now so the next member lands after this one
+        let offset = self.append(st, data.len());
+        st.modified = true;
+        
+        let extent = match open_pack {
+            Some(pack) => {
+                let pack_info = st.block_index.get_mut(&pack).unwrap();
+                let extent = PackedExtent {
+                    pack,
+                    offset: pack_info.size,
+                    len: data.len() as u32,
+                    hash,
+                };
+                pack_info.size += data.len() as u32;
+                pack_info.ref_count += 1;
+                self.usage.live_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+                extent
+            }
+            None => {
+                let pack = Self::new_pack_id(offset);
+                st.block_index.insert(pack, BlockInfo {
+                    offset,
+                    size: data.len() as u32,
+                    ref_count: 1,
+                    compression: Compression::None,
+                    stored_size: None,
+                    pack: true,
+                });
+                self.usage.add_block(data.len() as u64);
+                st.open_pack = Some(pack);
+                PackedExtent {
+                    pack,
+                    offset: 0,
+                    len: data.len() as u32,
+                    hash,
+                }
+            }
+        };
+        
+        let (mut state, write_result) = self.write_unlocked(state, hash, data, offset);
+        if let Err(e) = write_result {
+            // Not a member yet, so this only gives back the pack reference
+            self.release_packed(&mut state, &extent)?;
+            return Err(e.into());
+        }
+        
+        state.pack_members.insert(hash, PackMember { extent, refs: 1 });
+        Ok((extent, true))
+    }
+    
+    fn new_pack_id(offset: u64) -> BlockHash {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let mut hasher = Hasher::new();
+        hasher.update(b"unicache-pack");
+        hasher.update(&offset.to_le_bytes());
+        hasher.update(&nanos.to_le_bytes());
+        *hasher.finalize().as_bytes()
+    }
+    
+    pub fn read_packed(&self, extent: &PackedExtent, deadline: Deadline) -> Result<Vec<u8>> {
+        let (blocks_file, offset) = {
+            let state = self.state_until(deadline)?;
+            // Compaction moves members within their pack, so an extent looked
+            // up before it ran may be out of date
+            let extent = state.pack_members.get(&extent.hash)
+                .map_or(*extent, |member| member.extent);
+            let pack_info = state.block_index.get(&extent.pack)
+                .ok_or_else(|| BlockError::BlockNotFound(hex::encode(extent.pack)))?;
+            (state.blocks_file.clone(), pack_info.offset + extent.offset as u64)
+        };
+        
+        let mut buffer = vec![0u8; extent.len as usize];
+        read_exact_at(&blocks_file, &mut buffer, offset)?;
+        
+        Ok(buffer)
     }
-} 
\ No newline at end of file
+    
+    /// Drop one reference to a packed extent; returns true if its pack was freed.
+    ///
+    /// Once a member has no references left its bytes are dead, and stay in
+    /// the pack until compaction drops them.
+    fn release_packed(&self, state: &mut BlockState, extent: &PackedExtent) -> Result<bool> {
+        // An extent that never became a member (its write failed) is dead right away
+        let member_freed = match state.pack_members.entry(extent.hash) {
+            Entry::Occupied(mut member) if member.get().extent.pack == extent.pack => {
+                member.get_mut().refs -= 1;
+                if member.get().refs == 0 {
+                    member.remove();
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => true,
+        };
+        
+        let should_remove = match state.block_index.get_mut(&extent.pack) {
+            Some(pack_info) => {
+                pack_info.ref_count -= 1;
+                pack_info.ref_count == 0
+            }
+            None => return Err(BlockError::BlockNotFound(hex::encode(extent.pack))),
+        };
+        state.modified = true;
+        
+        if should_remove {
+            let pack_info = state.block_index.remove(&extent.pack).unwrap();
+            let dead = state.pack_dead.remove(&extent.pack).unwrap_or(0);
+            self.usage.remove_block((pack_info.stored_size() - dead) as u64);
+            if state.open_pack == Some(extent.pack) {
+                state.open_pack = None;
+            }
+        } else if member_freed {
+            *state.pack_dead.entry(extent.pack).or_default() += extent.len;
+            self.usage.live_bytes.fetch_sub(extent.len as u64, Ordering::Relaxed);
+        }
+        
+        Ok(should_remove)
+    }
+    
+    /// Record that the block or packed member with this content hash failed verification.
+    pub fn mark_corrupt(&self, hash: &BlockHash) {
+        self.state().corrupt.insert(*hash);
+    }
+    
+    pub fn clear_corrupt(&self, hash: &BlockHash) {
+        self.state().corrupt.remove(hash);
+    }
+    
+    /// Current blocks file, for page cache hints on ranges from `stored_range`.
+    pub fn blocks_file(&self) -> Arc<File> {
+        self.state().blocks_file.clone()
+    }
+    
+    /// Offset and stored length of a block or pack in the blocks file.
+    pub fn stored_range(&self, hash: &BlockHash) -> Option<(u64, u64)> {
+        self.state().block_index.get(hash)
+            .map(|info| (info.offset, info.stored_size() as u64))
+    }
+    
+    /// Length of the blocks file, including space no longer referenced.
+    /// Doesn't wait for the state lock, so it can be polled during compaction.
+    pub fn file_size(&self) -> u64 {
+        self.usage.file_bytes.load(Ordering::Relaxed)
+    }
+    
+    /// Bytes in the blocks file not occupied by any live block. Doesn't wait
+    /// for the state lock; a write in progress briefly counts as dead.
+    pub fn dead_bytes(&self) -> u64 {
+        self.file_size().saturating_sub(self.total_size())
+    }
+    
+    /// Current extent of the packed member with this content hash.
+    pub fn member_extent(&self, hash: &BlockHash) -> Option<PackedExtent> {
+        self.state().pack_members.get(hash).map(|member| member.extent)
+    }
+    
+    /// Current extent of every packed member, by content hash.
+    pub fn member_extents(&self) -> HashMap<BlockHash, PackedExtent> {
+        self.state().pack_members.iter()
+            .map(|(hash, member)| (*hash, member.extent))
+            .collect()
+    }
+    
+    /// Blocks at or past `from` in the blocks file, in offset order, with the
+    /// ranges to copy for each: the whole block, or a pack's live members.
+    fn plan_copy(state: &BlockState, from: u64) -> Vec<PlannedCopy> {
+        // Live members of each pack with dead bytes, in pack order
+        let mut repacked: HashMap<BlockHash, Vec<(BlockHash, u32, u32)>> = HashMap::new();
+        for (hash, member) in &state.pack_members {
+            if state.pack_dead.contains_key(&member.extent.pack) {
+                repacked.entry(member.extent.pack).or_default()
+                    .push((*hash, member.extent.offset, member.extent.len));
+            }
+        }
+        
+        let mut planned: Vec<PlannedCopy> = state.block_index.iter()
+            .filter(|(_, info)| info.offset >= from)
+            .map(|(hash, info)| match repacked.remove(hash) {
+                Some(mut members) => {
+                    members.sort_by_key(|&(_, offset, _)| offset);
+                    PlannedCopy {
+                        hash: *hash,
+                        offset: info.offset,
+                        ranges: members.iter()
+                            .map(|&(_, offset, len)| (info.offset + offset as u64, len as u64))
+                            .collect(),
+                        members: Some(members.iter().map(|&(hash, _, len)| (hash, len)).collect()),
+                    }
+                }
+                None => PlannedCopy {
+                    hash: *hash,
+                    offset: info.offset,
+                    ranges: vec![(info.offset, info.stored_size() as u64)],
+                    members: None,
+                },
+            })
+            .collect();
+        planned.sort_by_key(|block| block.offset);
+        planned
+    }
+    
+    /// Wait until every reserved range has been written.
+    fn settle<'a>(&'a self, mut state: MutexGuard<'a, BlockState>) -> MutexGuard<'a, BlockState> {
+        while !state.in_flight.is_empty() {
+            state = self.in_flight_done.wait(state).unwrap();
+        }
+        state
+    }
+    
+    /// Copy the live blocks, in offset order, into the next generation's
+    /// blocks file; `CompactionCopy::switch` then switches to it.
+    ///
+    /// Packs with dead members are copied member by member, closing the gaps,
+    /// which moves the remaining members within their pack; `member_extents`
+    /// gives their new positions after the switch.
+    ///
+    /// The copy runs without the state lock, so other operations go on
+    /// meanwhile. It is done in rounds, each picking up the blocks appended
+    /// during the last, and blocks rewritten in place are tracked to be copied
+    /// again, until what's left is small enough for `switch` to copy under
+    /// the lock.
+    ///
+    /// `cancel` and `deadline` are checked between blocks; a copy stopped by
+    /// either returns `None` and leaves the original file untouched.
+    /// `progress` receives the bytes copied so far and the total to copy.
+    pub fn copy_live(
+        &self,
+        cancel: &AtomicBool,
+        deadline: Deadline,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<Option<CompactionCopy<'_>>> {
+        let stop = || cancel.load(Ordering::Relaxed) || deadline.expired();
+        
+        let mut state = self.settle(self.state());
+        let path = self.blocks_path(state.generation + 1);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        
+        // Packs only grow at the end of the file, so none that is copied changes size
+        state.open_pack = None;
+        state.rewritten = Some(HashSet::new());
+        let mut planned = Self::plan_copy(&state, 0);
+        let mut copy = CompactionCopy {
+            store: self,
+            path,
+            file: Some(file),
+            scanned_end: state.end_offset,
+            copied: 0,
+            total: planned_bytes(&planned),
+            moved: HashMap::new(),
+        };
+        let source = state.blocks_file.clone();
+        drop(state);
+        
+        for _ in 0..MAX_CATCH_UP_ROUNDS {
+            if !copy.copy(&source, planned, &stop, &mut progress)? {
+                return Ok(None);
+            }
+            
+            let mut state = self.settle(self.state());
+            if state.end_offset - copy.scanned_end <= SWITCH_TAIL_BYTES {
+                break;
+            }
+            state.open_pack = None;
+            planned = Self::plan_copy(&state, copy.scanned_end);
+            copy.scanned_end = state.end_offset;
+            copy.total += planned_bytes(&planned);
+        }
+        
+        // So that the sync in `switch`, under the lock, only has the tail left to flush
+        copy.file.as_ref().unwrap().sync_all()?;
+        Ok(Some(copy))
+    }
+}
+
+/// Bytes appended since the last copy round that `CompactionCopy::switch`
+/// is left to copy with the state lock held.
+const SWITCH_TAIL_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Catch-up rounds after which the switch copies the rest regardless, so a
+/// steady stream of stores can't keep a compaction from finishing.
+const MAX_CATCH_UP_ROUNDS: usize = 4;
+
+/// A block or pack for compaction to copy.
+struct PlannedCopy {
+    hash: BlockHash,
+    offset: u64,
+    /// Ranges of the old file to copy back to back.
+    ranges: Vec<(u64, u64)>,
+    /// Content hash and length of each member, if a pack is copied member by member.
+    members: Option<Vec<(BlockHash, u32)>>,
+}
+
+fn planned_bytes(planned: &[PlannedCopy]) -> u64 {
+    planned.iter()
+        .flat_map(|block| &block.ranges)
+        .map(|&(_, len)| len)
+        .sum()
+}
+
+/// Where compaction copied a block or pack to.
+struct Moved {
+    old_offset: u64,
+    new_offset: u64,
+    new_size: u32,
+    /// Content hash, new offset within the pack and length of each member,
+    /// if the pack was copied member by member.
+    members: Option<Vec<(BlockHash, u32, u32)>>,
+}
+
+/// Live data copied into the next generation's blocks file by
+/// `BlockStore::copy_live`. Dropping it without switching removes the file.
+pub struct CompactionCopy<'a> {
+    store: &'a BlockStore,
+    path: PathBuf,
+    /// Taken by a successful switch.
+    file: Option<File>,
+    /// End of the part of the old file that copy rounds have planned.
+    scanned_end: u64,
+    copied: u64,
+    total: u64,
+    moved: HashMap<BlockHash, Moved>,
+}
+
+impl CompactionCopy<'_> {
+    /// Append `planned` to the new file. Returns false if `stop` interrupted it.
+    fn copy(
+        &mut self,
+        source: &File,
+        planned: Vec<PlannedCopy>,
+        stop: &impl Fn() -> bool,
+        progress: &mut impl FnMut(u64, u64),
+    ) -> io::Result<bool> {
+        let file = self.file.as_ref().unwrap();
+        let mut buffer = Vec::new();
+        for block in planned {
+            if stop() {
+                return Ok(false);
+            }
+            
+            let new_offset = self.copied;
+            for &(offset, len) in &block.ranges {
+                buffer.resize(len as usize, 0);
+                read_exact_at(source, &mut buffer, offset)?;
+                write_all_at(file, &buffer, self.copied)?;
+                self.copied += len;
+            }
+            
+            // Members land back to back, in pack order
+            let members = block.members.map(|members| {
+                let mut member_offset = 0;
+                members.into_iter()
+                    .map(|(hash, len)| {
+                        member_offset += len;
+                        (hash, member_offset - len, len)
+                    })
+                    .collect()
+            });
+            self.moved.insert(block.hash, Moved {
+                old_offset: block.offset,
+                new_offset,
+                new_size: (self.copied - new_offset) as u32,
+                members,
+            });
+            progress(self.copied, self.total);
+        }
+        Ok(true)
+    }
+    
+    /// Copy what was appended or rewritten in place since the last round, and
+    /// switch the store over to the new file.
+    ///
+    /// The state lock is held throughout, so this should follow `copy_live`
+    /// right away. The new file is synced but the old one is left in place:
+    /// until an index naming the new generation is durably saved, the index
+    /// on disk still describes the old file. The caller deletes it after that save.
+    pub fn switch(mut self) -> Result<CompactionOutcome> {
+        let store = self.store;
+        let mut state = store.settle(store.state());
+        self.switch_locked(&mut state)
+    }
+    
+    fn switch_locked(&mut self, state: &mut BlockState) -> Result<CompactionOutcome> {
+        let source = state.blocks_file.clone();
+        let planned = BlockStore::plan_copy(state, self.scanned_end);
+        self.total += planned_bytes(&planned);
+        self.copy(&source, planned, &|| false, &mut |_, _| {})?;
+        
+        let mut buffer = Vec::new();
+        for hash in state.rewritten.take().unwrap_or_default() {
+            for (from, to, len) in self.copied_ranges(state, &hash) {
+                buffer.resize(len as usize, 0);
+                read_exact_at(&source, &mut buffer, from)?;
+                write_all_at(self.file.as_ref().unwrap(), &buffer, to)?;
+            }
+        }
+        self.file.as_ref().unwrap().sync_all()?;
+        
+        let mut blocks_moved = 0;
+        for (hash, moved) in &self.moved {
+            let info = match state.block_index.get_mut(hash) {
+                Some(info) if info.offset == moved.old_offset => info,
+                // Removed during the copy, maybe stored again further on
+                _ => continue,
+            };
+            if info.offset != moved.new_offset || info.stored_size() != moved.new_size {
+                info.offset = moved.new_offset;
+                blocks_moved += 1;
+            }
+            if info.pack {
+                info.size = moved.new_size;
+            }
+            
+            if let Some(members) = &moved.members {
+                // Members released during the copy are dead in the new pack too
+                let mut dead = 0;
+                for &(member_hash, offset, len) in members {
+                    match state.pack_members.get_mut(&member_hash) {
+                        Some(member) if member.extent.pack == *hash => member.extent.offset = offset,
+                        _ => dead += len,
+                    }
+                }
+                match dead {
+                    0 => state.pack_dead.remove(hash),
+                    dead => state.pack_dead.insert(*hash, dead),
+                };
+            }
+        }
+        
+        let bytes_before = state.end_offset;
+        state.blocks_file = Arc::new(self.file.take().unwrap());
+        state.generation += 1;
+        state.end_offset = self.copied;
+        self.store.usage.file_bytes.store(self.copied, Ordering::Relaxed);
+        state.modified = true;
+        
+        Ok(CompactionOutcome {
+            blocks_moved,
+            bytes_before,
+            bytes_after: self.copied,
+            cancelled: false,
+        })
+    }
+    
+    /// Old and new offset, and length, of the copies of the block and the
+    /// packed member with this content hash, as currently stored.
+    fn copied_ranges(&self, state: &BlockState, hash: &BlockHash) -> Vec<(u64, u64, u64)> {
+        let mut ranges = Vec::new();
+        let copied = |hash: &BlockHash, info: &BlockInfo| self.moved.get(hash)
+            .filter(|moved| moved.old_offset == info.offset);
+        
+        if let Some(info) = state.block_index.get(hash) {
+            if let Some(moved) = copied(hash, info) {
+                ranges.push((info.offset, moved.new_offset, info.stored_size() as u64));
+            }
+        }
+        
+        if let Some(member) = state.pack_members.get(hash) {
+            let extent = member.extent;
+            let pack_info = state.block_index.get(&extent.pack);
+            if let Some(moved) = pack_info.and_then(|info| copied(&extent.pack, info)) {
+                let new_offset = match &moved.members {
+                    Some(members) => members.iter().find(|(member_hash, _, _)| member_hash == hash).map(|m| m.1),
+                    None => Some(extent.offset),
+                };
+                if let Some(new_offset) = new_offset {
+                    ranges.push((
+                        moved.old_offset + extent.offset as u64,
+                        moved.new_offset + new_offset as u64,
+                        extent.len as u64,
+                    ));
+                }
+            }
+        }
+        
+        ranges
+    }
+}
+
+impl Drop for CompactionCopy<'_> {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            drop(file);
+            let _ = fs::remove_file(&self.path);
+            self.store.state().rewritten = None;
+        }
+    }
+}
+
+/// Name of the blocks file of a generation. Generation 0 keeps the name used
+/// before compaction existed, so older caches open unchanged.
+fn blocks_file_name(generation: u64) -> String {
+    match generation {
+        0 => "blocks.bin".to_string(),
+        n => format!("blocks.{}.bin", n),
+    }
+}
+
+fn is_blocks_file_name(name: &str) -> bool {
+    // `blocks.bin.compact` is what earlier versions compacted into
+    name == "blocks.bin" || name == "blocks.bin.compact" || name.strip_prefix("blocks.")
+        .and_then(|rest| rest.strip_suffix(".bin"))
+        .is_some_and(|generation| generation.parse::<u64>().is_ok())
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset)? {
+            0 => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer")),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    #[test]
+    fn compaction_past_its_deadline_leaves_the_file_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlockStore::new(dir.path(), 0).unwrap();
+        let kept = store.store_block(&[1u8; 4096], Deadline::NONE).unwrap().0;
+        let removed = store.store_block(&[2u8; 4096], Deadline::NONE).unwrap().0;
+        store.release(&[removed], None, Deadline::NONE).unwrap();
+        
+        let expired = Deadline::after(Some(Duration::ZERO));
+        assert!(store.copy_live(&AtomicBool::new(false), expired, |_, _| {}).unwrap().is_none());
+        
+        assert_eq!(store.file_size(), 8192);
+        assert!(!store.blocks_path(1).exists());
+        assert!(store.state().rewritten.is_none());
+        assert_eq!(store.read_block(&kept, Deadline::NONE).unwrap(), vec![1u8; 4096]);
+    }
+    
+    #[test]
+    fn changes_made_while_compaction_copies_survive_the_switch() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlockStore::new(dir.path(), 0).unwrap();
+        let block = |fill: u8| vec![fill; 4096];
+        let repaired = store.store_block(&block(1), Deadline::NONE).unwrap().0;
+        let released = store.store_block(&block(2), Deadline::NONE).unwrap().0;
+        let dead = store.store_block(&block(3), Deadline::NONE).unwrap().0;
+        let dead_member = store.store_packed(&[4u8; 100], 4096, Deadline::NONE).unwrap().0;
+        let kept_member = store.store_packed(&[5u8; 100], 4096, Deadline::NONE).unwrap().0;
+        let released_member = store.store_packed(&[6u8; 100], 4096, Deadline::NONE).unwrap().0;
+        store.release(&[dead], Some(&dead_member), Deadline::NONE).unwrap();
+        
+        write_all_at(&store.blocks_file(), &block(0), 0).unwrap();
+        store.mark_corrupt(&repaired);
+        
+        let copy = store.copy_live(&AtomicBool::new(false), Deadline::NONE, |_, _| {}).unwrap().unwrap();
+        
+        // Repair a block already copied, release a block and a member already
+        // copied, and append a block and a member
+        store.store_block(&block(1), Deadline::NONE).unwrap();
+        store.release(&[released], Some(&released_member), Deadline::NONE).unwrap();
+        let appended = store.store_block(&block(7), Deadline::NONE).unwrap().0;
+        let appended_member = store.store_packed(&[8u8; 100], 4096, Deadline::NONE).unwrap().0;
+        
+        let outcome = copy.switch().unwrap();
+        assert_eq!(outcome.bytes_after, store.file_size());
+        assert!(store.blocks_path(1).exists());
+        assert!(store.state().rewritten.is_none());
+        assert_eq!(store.member_extent(&kept_member.hash).unwrap().offset, 0);
+        
+        let check = |store: &BlockStore| {
+            assert_eq!(store.read_block(&repaired, Deadline::NONE).unwrap(), block(1));
+            assert_eq!(store.read_block(&appended, Deadline::NONE).unwrap(), block(7));
+            assert_eq!(store.read_packed(&kept_member, Deadline::NONE).unwrap(), vec![5u8; 100]);
+            assert_eq!(store.read_packed(&appended_member, Deadline::NONE).unwrap(), vec![8u8; 100]);
+            assert!(store.block_info(&released).is_none());
+        };
+        check(&store);
+        
+        // What was released during the copy is dead in the new file, and goes next time
+        assert_eq!(store.dead_bytes(), 4096 + 100);
+        store.copy_live(&AtomicBool::new(false), Deadline::NONE, |_, _| {}).unwrap().unwrap().switch().unwrap();
+        assert_eq!(store.dead_bytes(), 0);
+        check(&store);
+    }
+    
+    #[test]
+    fn release_past_its_deadline_keeps_every_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlockStore::new(dir.path(), 0).unwrap();
+        let block = store.store_block(&[1u8; 4096], Deadline::NONE).unwrap().0;
+        let member = store.store_packed(&[2u8; 100], 4096, Deadline::NONE).unwrap().0;
+        
+        {
+            let _state = store.state();
+            let expired = Deadline::after(Some(Duration::from_millis(20)));
+            assert!(matches!(store.release(&[block], Some(&member), expired), Err(BlockError::Timeout(_))));
+        }
+        
+        assert_eq!(store.block_info(&block).unwrap().ref_count, 1);
+        assert_eq!(store.block_info(&member.pack).unwrap().ref_count, 1);
+        assert_eq!(store.member_extent(&member.hash), Some(member));
+        assert_eq!(store.dead_bytes(), 0);
+    }
+    
+    #[test]
+    fn stores_report_whether_they_wrote_the_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlockStore::new(dir.path(), 0).unwrap();
+        
+        let (block, written) = store.store_block(&[1u8; 4096], Deadline::NONE).unwrap();
+        assert!(written);
+        assert!(!store.store_block(&[1u8; 4096], Deadline::NONE).unwrap().1);
+        store.mark_corrupt(&block);
+        assert!(store.store_block(&[1u8; 4096], Deadline::NONE).unwrap().1);
+        
+        let (member, written) = store.store_packed(&[2u8; 100], 4096, Deadline::NONE).unwrap();
+        assert!(written);
+        assert!(!store.store_packed(&[2u8; 100], 4096, Deadline::NONE).unwrap().1);
+        store.mark_corrupt(&member.hash);
+        assert!(store.store_packed(&[2u8; 100], 4096, Deadline::NONE).unwrap().1);
+    }
+}
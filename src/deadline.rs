@@ -0,0 +1,77 @@
+//! Optional deadlines for cache operations.
+//!
+//! Deadlines are checked between I/O steps and while waiting for locks. A
+//! single system call that hangs (e.g. on an unresponsive network filesystem)
+//! can't be interrupted, but the operation gives up as soon as it returns.
+
+use std::sync::{Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError, TryLockResult};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub const NONE: Deadline = Deadline(None);
+    
+    pub fn after(timeout: Option<Duration>) -> Self {
+        Deadline(timeout.map(|timeout| Instant::now() + timeout))
+    }
+    
+    pub fn expired(&self) -> bool {
+        self.0.is_some_and(|at| Instant::now() >= at)
+    }
+    
+    /// Acquire `mutex`, giving up with `None` once the deadline passes.
+    pub fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> Option<MutexGuard<'a, T>> {
+        match self.0 {
+            Some(at) => Self::retry(at, || mutex.try_lock()),
+            None => Some(mutex.lock().unwrap()),
+        }
+    }
+    
+    /// Acquire `rwlock` for reading, giving up with `None` once the deadline passes.
+    pub fn read<'a, T>(&self, rwlock: &'a RwLock<T>) -> Option<RwLockReadGuard<'a, T>> {
+        match self.0 {
+            Some(at) => Self::retry(at, || rwlock.try_read()),
+            None => Some(rwlock.read().unwrap()),
+        }
+    }
+    
+    /// Acquire `rwlock` for writing, giving up with `None` once the deadline passes.
+    pub fn write<'a, T>(&self, rwlock: &'a RwLock<T>) -> Option<RwLockWriteGuard<'a, T>> {
+        match self.0 {
+            Some(at) => Self::retry(at, || rwlock.try_write()),
+            None => Some(rwlock.write().unwrap()),
+        }
+    }
+    
+    fn retry<G>(at: Instant, mut try_lock: impl FnMut() -> TryLockResult<G>) -> Option<G> {
+        let mut backoff = Duration::from_micros(50);
+        loop {
+            match try_lock() {
+                Ok(guard) => return Some(guard),
+                Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+                Err(TryLockError::WouldBlock) => {}
+            }
+            
+            let now = Instant::now();
+            if now >= at {
+                return None;
+            }
+            std::thread::sleep(backoff.min(at - now));
+            backoff = (backoff * 2).min(Duration::from_millis(10));
+        }
+    }
+    
+    /// Wait on `condvar`, giving up with `None` once the deadline passes.
+    pub fn wait<'a, T>(&self, condvar: &Condvar, guard: MutexGuard<'a, T>) -> Option<MutexGuard<'a, T>> {
+        let at = match self.0 {
+            Some(at) => at,
+            None => return Some(condvar.wait(guard).unwrap()),
+        };
+        
+        let remaining = at.checked_duration_since(Instant::now())?;
+        let (guard, _) = condvar.wait_timeout(guard, remaining).unwrap();
+        Some(guard)
+    }
+}
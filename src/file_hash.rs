@@ -0,0 +1,81 @@
+use std::fmt;
+use std::str::FromStr;
+use md5::Md5;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+/// Algorithm used for the whole-file digest, independent of the block hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileHashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+    /// Only for interop with systems that still key on MD5.
+    Md5,
+}
+
+impl FileHashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileHashAlgorithm::Blake3 => "blake3",
+            FileHashAlgorithm::Sha256 => "sha256",
+            FileHashAlgorithm::Md5 => "md5",
+        }
+    }
+    
+    pub fn hasher(&self) -> FileHasher {
+        match self {
+            FileHashAlgorithm::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+            FileHashAlgorithm::Sha256 => FileHasher::Sha256(Sha256::new()),
+            FileHashAlgorithm::Md5 => FileHasher::Md5(Md5::new()),
+        }
+    }
+}
+
+impl fmt::Display for FileHashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for FileHashAlgorithm {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "blake3" => Ok(FileHashAlgorithm::Blake3),
+            "sha256" => Ok(FileHashAlgorithm::Sha256),
+            "md5" => Ok(FileHashAlgorithm::Md5),
+            _ => Err(format!(
+                "Unknown file hash algorithm '{}' (expected blake3, sha256 or md5)", s
+            )),
+        }
+    }
+}
+
+/// Incremental hasher for whichever algorithm was selected.
+pub enum FileHasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+    Md5(Md5),
+}
+
+impl FileHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Blake3(h) => { h.update(data); }
+            FileHasher::Sha256(h) => h.update(data),
+            FileHasher::Md5(h) => h.update(data),
+        }
+    }
+    
+    /// Hex-encoded digest.
+    pub fn finalize(self) -> String {
+        match self {
+            FileHasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            FileHasher::Sha256(h) => hex::encode(h.finalize()),
+            FileHasher::Md5(h) => hex::encode(h.finalize()),
+        }
+    }
+}
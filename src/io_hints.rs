@@ -0,0 +1,179 @@
+//! Page cache hints for streaming I/O.
+//!
+//! Ingesting or retrieving a very large file otherwise pulls every byte through
+//! the page cache and evicts the rest of the machine's working set. These are
+//! advisory only: failures are ignored, and they are no-ops on platforms
+//! without `posix_fadvise`.
+
+use std::borrow::Borrow;
+use std::fs::File;
+use std::mem;
+
+/// Bytes written before the previous window is flushed and dropped.
+const WINDOW_BYTES: u64 = 8 * 1024 * 1024;
+
+/// The whole file will be read or written front to back.
+pub fn advise_sequential(file: &File) {
+    fadvise(file, 0, 0, Advice::Sequential);
+}
+
+/// Let the kernel drop a range from the page cache. Only clean pages go.
+fn advise_dontneed(file: &File, offset: u64, len: u64) {
+    if len > 0 {
+        fadvise(file, offset, len, Advice::DontNeed);
+    }
+}
+
+/// Drops ranges of a file from the page cache one window behind the reader
+/// or writer.
+///
+/// `POSIX_FADV_DONTNEED` skips dirty pages and pages under writeback, so
+/// advising right after a write does nothing. Writeback of each released
+/// range is started right away instead, and once a window's worth of bytes
+/// has been released, the previous window is waited on, which by then has
+/// usually reached the disk, and dropped. Adjacent ranges are merged and the
+/// window before is covered again, because a large folio straddling two
+/// ranges is only dropped by a call spanning all of it. Whatever is left is
+/// dropped when the `DropBehind` goes out of scope.
+pub struct DropBehind<F: Borrow<File>> {
+    file: F,
+    current: Vec<(u64, u64)>,
+    current_bytes: u64,
+    /// Released before `current`; writeback is under way.
+    previous: Vec<(u64, u64)>,
+    /// Released before `previous`; already dropped.
+    dropped: Vec<(u64, u64)>,
+}
+
+impl<F: Borrow<File>> DropBehind<F> {
+    pub fn new(file: F) -> Self {
+        DropBehind {
+            file,
+            current: Vec::new(),
+            current_bytes: 0,
+            previous: Vec::new(),
+            dropped: Vec::new(),
+        }
+    }
+    
+    /// Report a range that was just read or written and won't be needed again.
+    pub fn release(&mut self, offset: u64, len: u64) {
+        if len == 0 || !HAS_FADVISE {
+            return;
+        }
+        
+        start_writeback(self.file.borrow(), offset, len);
+        self.current.push((offset, len));
+        self.current_bytes += len;
+        
+        if self.current_bytes >= WINDOW_BYTES {
+            let window = mem::replace(&mut self.previous, mem::take(&mut self.current));
+            self.current_bytes = 0;
+            self.drop_window(window);
+        }
+    }
+    
+    fn drop_window(&mut self, window: Vec<(u64, u64)>) {
+        if window.is_empty() {
+            return;
+        }
+        
+        let file = self.file.borrow();
+        let merged = coalesce(window);
+        wait_writeback(file, &merged);
+        
+        let mut ranges = mem::take(&mut self.dropped);
+        ranges.extend_from_slice(&merged);
+        for (offset, len) in coalesce(ranges) {
+            advise_dontneed(file, offset, len);
+        }
+        self.dropped = merged;
+    }
+}
+
+impl<F: Borrow<File>> Drop for DropBehind<F> {
+    fn drop(&mut self) {
+        let mut window = mem::take(&mut self.previous);
+        window.append(&mut self.current);
+        self.drop_window(window);
+    }
+}
+
+/// Sort ranges and merge those that touch or overlap.
+fn coalesce(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (offset, len) in ranges {
+        match merged.last_mut() {
+            Some(last) if offset <= last.0 + last.1 => {
+                last.1 = last.1.max(offset + len - last.0);
+            }
+            _ => merged.push((offset, len)),
+        }
+    }
+    merged
+}
+
+enum Advice {
+    Sequential,
+    DontNeed,
+}
+
+const HAS_FADVISE: bool = cfg!(any(target_os = "linux", target_os = "android", target_os = "freebsd"));
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn fadvise(file: &File, offset: u64, len: u64, advice: Advice) {
+    use std::os::unix::io::AsRawFd;
+    
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    
+    // Advisory only, so the return value is deliberately ignored
+    unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            advice,
+        );
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn fadvise(_file: &File, _offset: u64, _len: u64, _advice: Advice) {}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sync_file_range(file: &File, offset: u64, len: u64, flags: libc::c_uint) {
+    use std::os::unix::io::AsRawFd;
+    
+    unsafe {
+        libc::sync_file_range(file.as_raw_fd(), offset as libc::off64_t, len as libc::off64_t, flags);
+    }
+}
+
+/// Queue a range for writeback without waiting for it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn start_writeback(file: &File, offset: u64, len: u64) {
+    sync_file_range(file, offset, len, libc::SYNC_FILE_RANGE_WRITE);
+}
+
+/// Write back the given ranges and wait until they are clean.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn wait_writeback(file: &File, ranges: &[(u64, u64)]) {
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    for &(offset, len) in ranges {
+        sync_file_range(file, offset, len, flags);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn start_writeback(_file: &File, _offset: u64, _len: u64) {}
+
+// Without sync_file_range the whole file's data has to be flushed; this runs
+// once per window, not per range
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn wait_writeback(file: &File, _ranges: &[(u64, u64)]) {
+    let _ = file.sync_data();
+}
@@ -1,19 +1,33 @@
+// pyo3 0.19's #[pymethods] expansion trips this lint on newer toolchains.
+#![allow(non_local_definitions)]
+
 mod block;
+mod deadline;
+mod file_hash;
+mod io_hints;
+mod maintenance;
+mod restic;
 
 use pyo3::prelude::*;
-use pyo3::exceptions::{PyIOError, PyValueError};
-use pyo3::types::PyBytes;
-use std::fs::{self, File};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyIOError, PyTimeoutError, PyValueError};
+use pyo3::types::PyDict;
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use blake3::Hasher;
 use serde::{Serialize, Deserialize};
-use std::sync::{Arc, Mutex};
-use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
-use block::{BlockStore, BlockHash, BlockInfo, BlockError};
+use block::{write_all_at, BlockStore, BlockHash, BlockInfo, BlockError, Compression, CompactionOutcome, CompressionTotals, PackedExtent};
+use deadline::Deadline;
+use file_hash::FileHashAlgorithm;
+use io_hints::DropBehind;
+use maintenance::{CompactionStatus, CompactionTracker, CompactionTrigger, MaintenancePolicy};
 
 #[derive(Error, Debug)]
 pub enum CacheError {
@@ -26,6 +40,18 @@ pub enum CacheError {
     #[error("File not found: {0}")]
     FileNotFound(String),
     
+    #[error("Timed out {0}")]
+    Timeout(String),
+    
+    #[error("File is quarantined after failing verification: {0}")]
+    Quarantined(String),
+    
+    #[error("Invalid range: {0}")]
+    InvalidRange(String),
+    
+    #[error("Import error: {0}")]
+    Import(#[from] restic::ResticError),
+    
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
     
@@ -35,186 +61,1431 @@ pub enum CacheError {
 
 type Result<T> = std::result::Result<T, CacheError>;
 
-#[derive(Debug, Serialize, Deserialize)]
+impl CacheError {
+    fn is_timeout(&self) -> bool {
+        matches!(self, CacheError::Timeout(_) | CacheError::Block(BlockError::Timeout(_)))
+    }
+}
+
+create_exception!(
+    unicache_rs,
+    CorruptionError,
+    PyIOError,
+    "Raised when retrieving a file that is quarantined because verification found corrupt data."
+);
+
+fn to_py_err(e: CacheError) -> PyErr {
+    match e {
+        CacheError::InvalidRange(_) => PyValueError::new_err(e.to_string()),
+        CacheError::Quarantined(_) => CorruptionError::new_err(e.to_string()),
+        _ if e.is_timeout() => PyTimeoutError::new_err(e.to_string()),
+        _ => PyIOError::new_err(e.to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct FileInfo {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     blocks: Vec<BlockHash>,
     size: u64,
     name: String,
+    /// Hex digest of the whole file; absent for entries stored before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash_algorithm: Option<FileHashAlgorithm>,
+    /// Set for small files stored inside a pack block; `blocks` is then empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    packed: Option<PackedExtent>,
+    /// Milliseconds since the Unix epoch of the last store or retrieval, for
+    /// eviction. Retrievals only mark the index dirty, so a crash can lose
+    /// recent updates; entries without one are treated as least recently used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_access: Option<u64>,
+    /// Set once verification finds corrupt data; retrievals fail until it is
+    /// cleared or the entry is stored again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quarantine: Option<Quarantine>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Quarantine {
+    /// Milliseconds since the Unix epoch.
+    detected_at: u64,
+    /// Hex content hashes of the blocks or packed extent that failed
+    /// verification; empty if only the whole-file digest did.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    corrupt_blocks: Vec<String>,
+}
+
+/// One contiguous piece of a file's content as stored in the blocks file.
+#[derive(Clone, Copy)]
+enum FilePart<'a> {
+    Block(&'a BlockHash),
+    Packed(&'a PackedExtent),
+}
+
+impl FilePart<'_> {
+    /// BLAKE3 of the part's bytes as recorded at ingest.
+    fn content_hash(&self) -> &BlockHash {
+        match self {
+            FilePart::Block(hash) => hash,
+            FilePart::Packed(extent) => &extent.hash,
+        }
+    }
+}
+
+impl FileInfo {
+    fn parts(&self) -> Vec<FilePart<'_>> {
+        match &self.packed {
+            Some(extent) => vec![FilePart::Packed(extent)],
+            None => self.blocks.iter().map(FilePart::Block).collect(),
+        }
+    }
+}
+
+/// Per-file view of raw vs stored bytes, broken down by compression algorithm.
+struct FileStats {
+    name: String,
+    size: u64,
+    block_count: usize,
+    packed: bool,
+    stored_size: u64,
+    compression: HashMap<Compression, CompressionTotals>,
+    hash: Option<String>,
+    hash_algorithm: Option<FileHashAlgorithm>,
+    last_access: Option<u64>,
+    quarantined: bool,
+}
+
+/// Files the eviction policy would remove, least recently used first.
+struct EvictionPlan {
+    file_ids: Vec<String>,
+    /// Stored bytes that no remaining file references once these are gone.
+    freed_bytes: u64,
+    /// Combined size of the evicted files, before deduplication.
+    logical_bytes: u64,
+}
+
+/// Result of importing a snapshot from another backup tool's repository.
+struct ImportSummary {
+    snapshot: String,
+    /// Paths the snapshot was taken of, as recorded by the other tool.
+    paths: Vec<String>,
+    file_ids: Vec<String>,
+    bytes: u64,
+    /// Symlinks, devices and other entries with no file content.
+    skipped: usize,
+}
+
+/// Trailing element of the index tuple.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct IndexMeta {
+    /// Generation of the blocks file that block offsets refer to.
+    blocks_generation: u64,
+}
+
+/// index.json as read from disk. Indexes written before compaction switched
+/// blocks files have no `IndexMeta` and always refer to generation 0.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredIndex {
+    Current(HashMap<String, BlockInfo>, HashMap<String, FileInfo>, IndexMeta),
+    Legacy(HashMap<String, BlockInfo>, HashMap<String, FileInfo>),
+}
+
+/// Tracks in-progress operations and when the last one finished, for idle detection.
+struct Activity {
+    active_ops: usize,
+    last_finished: Instant,
 }
 
+/// Cache state shared between threads.
+///
+/// Writes to the same file_id are serialized through a per-entry lock that reads
+/// share, while
+/// the file index is only locked briefly to read or swap an entry. Lock order is
+/// per-entry lock, then `file_index`, then the block store's internal lock.
 struct CacheStorage {
     block_size: usize,
+    file_hash_algorithm: FileHashAlgorithm,
+    /// Default for whether streaming I/O advises the kernel to drop pages behind it.
+    page_cache_hints: bool,
+    /// Files smaller than this are packed into shared pack blocks; 0 disables packing.
+    small_file_threshold: usize,
     cache_dir: PathBuf,
     block_store: BlockStore,
-    file_index: HashMap<String, FileInfo>,
-    modified: bool,
+    file_index: RwLock<HashMap<String, FileInfo>>,
+    file_locks: Mutex<HashMap<String, Arc<RwLock<()>>>>,
+    /// Serializes index writes; holds the blocks file generation the index on disk names.
+    index_write_lock: Mutex<u64>,
+    modified: AtomicBool,
+    activity: Mutex<Activity>,
+    maintenance_policy: Mutex<MaintenancePolicy>,
+    maintenance_started: AtomicBool,
+    compaction: CompactionTracker,
 }
 
 impl CacheStorage {
-    fn new(block_size: usize, cache_dir: &Path) -> Result<Self> {
+    fn new(
+        block_size: usize,
+        cache_dir: &Path,
+        file_hash_algorithm: FileHashAlgorithm,
+        page_cache_hints: bool,
+        small_file_threshold: usize,
+    ) -> Result<Self> {
         fs::create_dir_all(cache_dir)?;
         
-        let blocks_path = cache_dir.join("blocks.bin");
         let index_path = cache_dir.join("index.json");
         
-        let mut block_store = BlockStore::new(&blocks_path)?;
-        
-        let (block_index, file_index) = if index_path.exists() {
+        let (block_index, file_index, meta) = if index_path.exists() {
             let index_data = fs::read_to_string(&index_path)?;
-            let index: (HashMap<String, BlockInfo>, HashMap<String, FileInfo>) = serde_json::from_str(&index_data)?;
+            let (block_index, file_index, meta) = match serde_json::from_str(&index_data)? {
+                StoredIndex::Current(blocks, files, meta) => (blocks, files, meta),
+                StoredIndex::Legacy(blocks, files) => (blocks, files, IndexMeta::default()),
+            };
             
             // Convert string keys back to BlockHash
-            let block_index = index.0.into_iter()
-                .filter_map(|(k, v)| {
-                    let hash = hex::decode(k).ok()?;
-                    if hash.len() == 32 {
-                        let mut block_hash = [0u8; 32];
-                        block_hash.copy_from_slice(&hash);
-                        Some((block_hash, v))
-                    } else {
-                        None
-                    }
-                })
+            let block_index = block_index.into_iter()
+                .filter_map(|(k, v)| Some((decode_block_hash(&k)?, v)))
                 .collect();
-                
-            (block_index, index.1)
+            
+            (block_index, file_index, meta)
         } else {
-            (HashMap::new(), HashMap::new())
+            (HashMap::new(), HashMap::new(), IndexMeta::default())
         };
         
+        let block_store = BlockStore::new(cache_dir, meta.blocks_generation)?;
         block_store.set_index(block_index);
+        block_store.restore_pack_members(file_index.values().filter_map(|info: &FileInfo| info.packed.as_ref()));
+        let corrupt_blocks = file_index.values()
+            .filter_map(|info| info.quarantine.as_ref())
+            .flat_map(|quarantine| &quarantine.corrupt_blocks);
+        for hash in corrupt_blocks.filter_map(|hex| decode_block_hash(hex)) {
+            block_store.mark_corrupt(&hash);
+        }
         
         Ok(CacheStorage {
             block_size,
+            file_hash_algorithm,
+            page_cache_hints,
+            small_file_threshold,
             cache_dir: cache_dir.to_path_buf(),
             block_store,
-            file_index,
-            modified: false,
+            file_index: RwLock::new(file_index),
+            file_locks: Mutex::new(HashMap::new()),
+            index_write_lock: Mutex::new(meta.blocks_generation),
+            modified: AtomicBool::new(false),
+            activity: Mutex::new(Activity {
+                active_ops: 0,
+                last_finished: Instant::now(),
+            }),
+            maintenance_policy: Mutex::new(MaintenancePolicy::default()),
+            maintenance_started: AtomicBool::new(false),
+            compaction: CompactionTracker::default(),
+        })
+    }
+    
+    /// Run `f` while holding the lock for `file_id` exclusively, waiting for it
+    /// no longer than `deadline`.
+    fn with_file_lock<T>(&self, file_id: &str, deadline: Deadline, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.with_entry_lock(file_id, |lock| match deadline.write(lock) {
+            Some(_guard) => f(),
+            None => Err(CacheError::Timeout(format!("waiting for the lock on {}", file_id))),
         })
     }
     
-    fn save_index(&self) -> Result<()> {
-        if !self.modified && !self.block_store.is_modified() {
+    /// Like `with_file_lock`, but shared with other readers of `file_id`.
+    fn with_file_read_lock<T>(&self, file_id: &str, deadline: Deadline, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.with_entry_lock(file_id, |lock| match deadline.read(lock) {
+            Some(_guard) => f(),
+            None => Err(CacheError::Timeout(format!("waiting for the lock on {}", file_id))),
+        })
+    }
+    
+    fn with_entry_lock<T>(&self, file_id: &str, f: impl FnOnce(&RwLock<()>) -> Result<T>) -> Result<T> {
+        let lock = self.file_locks.lock().unwrap()
+            .entry(file_id.to_string())
+            .or_default()
+            .clone();
+        
+        self.activity.lock().unwrap().active_ops += 1;
+        
+        let result = f(&lock);
+        
+        {
+            let mut activity = self.activity.lock().unwrap();
+            activity.active_ops -= 1;
+            activity.last_finished = Instant::now();
+        }
+        
+        // Drop the entry once no other thread is waiting on it
+        let mut file_locks = self.file_locks.lock().unwrap();
+        drop(lock);
+        if file_locks.get(file_id).is_some_and(|l| Arc::strong_count(l) == 1) {
+            file_locks.remove(file_id);
+        }
+        
+        result
+    }
+    
+    fn save_index(&self, deadline: Deadline) -> Result<()> {
+        if !self.modified.load(Ordering::Acquire) && !self.block_store.is_modified() {
             return Ok(());
         }
         
-        // Convert BlockHash to hex strings for JSON serialization
-        let block_index_hex: HashMap<String, BlockInfo> = self.block_store.get_index()
-            .iter()
-            .map(|(k, v)| (hex::encode(k), v.clone()))
-            .collect();
+        let mut saved_generation = self.lock_index_writes(deadline)?;
+        self.write_index(&mut saved_generation)
+    }
+    
+    /// Take `index_write_lock`, which compaction holds while it switches blocks files.
+    fn lock_index_writes(&self, deadline: Deadline) -> Result<MutexGuard<'_, u64>> {
+        deadline.lock(&self.index_write_lock)
+            .ok_or_else(|| CacheError::Timeout("waiting to save the index".to_string()))
+    }
+    
+    /// Write the index unconditionally. `saved_generation` is the value behind
+    /// `index_write_lock`, which the caller holds.
+    fn write_index(&self, saved_generation: &mut u64) -> Result<()> {
+        let (generation, index_data) = {
+            let file_index = self.file_index.read().unwrap();
+            let (generation, block_index) = self.block_store.index_snapshot();
+            
+            // Convert BlockHash to hex strings for JSON serialization
+            let block_index_hex: HashMap<String, BlockInfo> = block_index
+                .into_iter()
+                .map(|(k, v)| (hex::encode(k), v))
+                .collect();
             
-        let index_data = serde_json::to_string(&(block_index_hex, &self.file_index))?;
-        fs::write(self.cache_dir.join("index.json"), index_data)?;
+            let meta = IndexMeta { blocks_generation: generation };
+            (generation, serde_json::to_string(&(block_index_hex, &*file_index, meta))?)
+        };
+        
+        // Write through a temporary file so readers never observe a partial index
+        let index_path = self.cache_dir.join("index.json");
+        let tmp_path = self.cache_dir.join("index.json.tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(index_data.as_bytes())?;
+        
+        if generation == *saved_generation {
+            drop(tmp_file);
+            fs::rename(&tmp_path, &index_path)?;
+            return Ok(());
+        }
+        
+        // Compaction switched blocks files. The old file is what the index on
+        // disk describes, so it may only go once the new index is durable.
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+        fs::rename(&tmp_path, &index_path)?;
+        sync_dir(&self.cache_dir)?;
+        
+        for old in *saved_generation..generation {
+            let _ = fs::remove_file(self.block_store.blocks_path(old));
+        }
+        *saved_generation = generation;
         
         Ok(())
     }
     
-    fn store_file(&mut self, file_path: &Path, file_id: &str) -> Result<()> {
+    fn store_file(
+        &self,
+        file_path: &Path,
+        file_id: &str,
+        page_cache_hints: Option<bool>,
+        deadline: Deadline,
+    ) -> Result<()> {
+        let hints = page_cache_hints.unwrap_or(self.page_cache_hints);
+        self.with_file_lock(file_id, deadline, || self.store_file_locked(file_path, file_id, hints, deadline))
+    }
+    
+    fn store_file_locked(&self, file_path: &Path, file_id: &str, hints: bool, deadline: Deadline) -> Result<()> {
         let file = File::open(file_path)?;
         let file_size = file.metadata()?.len();
         let file_name = file_path.file_name()
             .ok_or_else(|| CacheError::Other("Invalid file path".to_string()))?
             .to_string_lossy()
             .to_string();
+        
+        let mut file_info = FileInfo {
+            blocks: Vec::new(),
+            size: file_size,
+            name: file_name,
+            hash: None,
+            hash_algorithm: Some(self.file_hash_algorithm),
+            packed: None,
+            last_access: Some(now_millis()),
+            quarantine: None,
+        };
+        
+        self.ingest(file_path, &mut file_info, hints, deadline)?;
+        
+        // Taken before the entry changes, so a timeout leaves the index untouched
+        let mut saved_generation = match self.lock_index_writes(deadline) {
+            Ok(saved_generation) => saved_generation,
+            Err(e) => {
+                let _ = self.release_parts(&file_info, Deadline::NONE);
+                return Err(e);
+            }
+        };
+        self.insert_entry(file_id, file_info, deadline)?;
+        self.write_index(&mut saved_generation)
+    }
+    
+    /// Add a fully ingested entry, releasing the blocks of any entry it replaces.
+    ///
+    /// If the old entry's blocks can't be released before `deadline`, the old
+    /// entry stays and the new one's references are given back.
+    fn insert_entry(&self, file_id: &str, mut file_info: FileInfo, deadline: Deadline) -> Result<()> {
+        let released = {
+            let mut file_index = self.file_index.write().unwrap();
             
-        let mut blocks = Vec::new();
+            // Released under the index lock, so no index is saved in between
+            let released = match file_index.get(file_id) {
+                Some(old_info) => self.release_parts(old_info, deadline),
+                None => Ok(()),
+            };
+            if released.is_ok() {
+                // A compaction since the member was stored may have moved it within
+                // its pack, and only updated the entries already in the index
+                if let Some(extent) = &mut file_info.packed {
+                    if let Some(current) = self.block_store.member_extent(&extent.hash) {
+                        *extent = current;
+                    }
+                }
+                file_index.insert(file_id.to_string(), file_info.clone());
+            }
+            released
+        };
+        
+        if released.is_err() {
+            let _ = self.release_parts(&file_info, Deadline::NONE);
+            return released;
+        }
+        
+        self.modified.store(true, Ordering::Release);
+        Ok(())
+    }
+    
+    fn ingest(&self, file_path: &Path, file_info: &mut FileInfo, hints: bool, deadline: Deadline) -> Result<()> {
+        let file = File::open(file_path)?;
+        if hints {
+            io_hints::advise_sequential(&file);
+        }
+        
+        let mut source = hints.then(|| DropBehind::new(&file));
+        self.ingest_from(&mut &file, file_info, hints, deadline, |offset, len| {
+            if let Some(source) = &mut source {
+                source.release(offset, len);
+            }
+        })
+    }
+    
+    /// Read `file_info.size` bytes from `source` into the block store, recording
+    /// each stored block (or the packed extent) in `file_info` as soon as its
+    /// reference is taken. `consumed` is called with the offset and length of
+    /// each chunk once it has been stored.
+    ///
+    /// If reading or storing fails or times out, the references taken so far
+    /// are given back, leaving the block store as it was.
+    fn ingest_from(
+        &self,
+        source: &mut impl Read,
+        file_info: &mut FileInfo,
+        hints: bool,
+        deadline: Deadline,
+        consumed: impl FnMut(u64, u64),
+    ) -> Result<()> {
+        let result = self.ingest_chunks(source, file_info, hints, deadline, consumed);
+        if result.is_err() {
+            let _ = self.release_parts(file_info, Deadline::NONE);
+        }
+        result
+    }
+    
+    fn ingest_chunks(
+        &self,
+        source: &mut impl Read,
+        file_info: &mut FileInfo,
+        hints: bool,
+        deadline: Deadline,
+        mut consumed: impl FnMut(u64, u64),
+    ) -> Result<()> {
+        let file_size = file_info.size;
         
         // Process file in chunks using memory mapping for efficiency
         let chunk_size = 10 * 1024 * 1024; // 10MB chunks for processing
-        let mut file = File::open(file_path)?;
         let mut buffer = vec![0u8; chunk_size];
+        let mut file_hasher = self.file_hash_algorithm.hasher();
+        
+        // Small files share pack blocks instead of getting a block entry each
+        let pack_file = file_size > 0
+            && file_size < self.small_file_threshold as u64
+            && file_size <= chunk_size as u64;
+        
+        let mut drop_behind = hints.then(|| DropBehind::new(self.block_store.blocks_file()));
         
         let mut remaining = file_size;
         while remaining > 0 {
             let to_read = std::cmp::min(remaining, chunk_size as u64) as usize;
             let buffer = &mut buffer[..to_read];
-            file.read_exact(buffer)?;
+            source.read_exact(buffer)?;
+            file_hasher.update(buffer);
             
-            // Split chunk into blocks and store them
-            for chunk in buffer.chunks(self.block_size) {
-                let hash = self.block_store.store_block(chunk)?;
-                blocks.push(hash);
+            if pack_file {
+                let pack_limit = self.block_size.max(self.small_file_threshold);
+                let (extent, written) = self.block_store.store_packed(buffer, pack_limit, deadline)?;
+                if written {
+                    self.drop_behind_part(&mut drop_behind, FilePart::Packed(&extent));
+                }
+                file_info.packed = Some(extent);
+            } else {
+                // Split chunk into blocks and store them
+                for chunk in buffer.chunks(self.block_size) {
+                    if deadline.expired() {
+                        return Err(CacheError::Timeout("storing file".to_string()));
+                    }
+                    // Deduplicated blocks weren't written here, and may be
+                    // hot for other readers
+                    let (hash, written) = self.block_store.store_block(chunk, deadline)?;
+                    if written {
+                        self.drop_behind_part(&mut drop_behind, FilePart::Block(&hash));
+                    }
+                    file_info.blocks.push(hash);
+                }
             }
             
+            consumed(file_size - remaining, to_read as u64);
             remaining -= to_read as u64;
         }
         
-        // Store file info
-        let file_info = FileInfo {
-            blocks,
-            size: file_size,
-            name: file_name,
+        file_info.hash = Some(file_hasher.finalize());
+        Ok(())
+    }
+    
+    fn release_parts(&self, file_info: &FileInfo, deadline: Deadline) -> Result<()> {
+        match &file_info.packed {
+            Some(extent) => self.block_store.release(&[], Some(extent), deadline)?,
+            None => self.block_store.release(&file_info.blocks, None, deadline)?,
+        }
+        Ok(())
+    }
+    
+    fn read_part(&self, part: FilePart<'_>, deadline: Deadline) -> Result<Vec<u8>> {
+        if deadline.expired() {
+            return Err(CacheError::Timeout("reading file".to_string()));
+        }
+        Ok(match part {
+            FilePart::Block(hash) => self.block_store.read_block(hash, deadline)?,
+            FilePart::Packed(extent) => self.block_store.read_packed(extent, deadline)?,
+        })
+    }
+    
+    /// Hand a part's bytes in the blocks file to `drop_behind`, if hints are on.
+    ///
+    /// The range is looked up in the current blocks file; if a compaction
+    /// swapped it since `drop_behind` was created, the hint just misses.
+    fn drop_behind_part(&self, drop_behind: &mut Option<DropBehind<Arc<File>>>, part: FilePart<'_>) {
+        if let Some(drop_behind) = drop_behind {
+            let range = match part {
+                FilePart::Block(hash) => self.block_store.stored_range(hash),
+                FilePart::Packed(extent) => self.block_store.stored_range(&extent.pack)
+                    .map(|(offset, _)| (offset + extent.offset as u64, extent.len as u64)),
+            };
+            if let Some((offset, len)) = range {
+                drop_behind.release(offset, len);
+            }
+        }
+    }
+    
+    fn lookup_file(&self, file_id: &str) -> Result<FileInfo> {
+        self.file_index.read().unwrap()
+            .get(file_id)
+            .cloned()
+            .ok_or_else(|| CacheError::FileNotFound(file_id.to_string()))
+    }
+    
+    /// Like `lookup_file`, but refuses quarantined entries so corrupt data is never served.
+    fn lookup_for_read(&self, file_id: &str) -> Result<FileInfo> {
+        let file_info = self.lookup_file(file_id)?;
+        if file_info.quarantine.is_some() {
+            return Err(CacheError::Quarantined(file_id.to_string()));
+        }
+        Ok(file_info)
+    }
+    
+    fn retrieve_file(
+        &self,
+        file_id: &str,
+        output_path: &Path,
+        page_cache_hints: Option<bool>,
+        deadline: Deadline,
+    ) -> Result<()> {
+        let hints = page_cache_hints.unwrap_or(self.page_cache_hints);
+        
+        self.with_file_read_lock(file_id, deadline, || {
+            let file_info = self.lookup_for_read(file_id)?;
+            
+            let output_file = File::create(output_path)?;
+            if hints {
+                io_hints::advise_sequential(&output_file);
+            }
+            
+            // Don't leave a truncated file behind on failure or timeout
+            let result = self.write_parts(&file_info, &output_file, hints, deadline);
+            if result.is_err() {
+                drop(output_file);
+                let _ = fs::remove_file(output_path);
+            } else {
+                self.touch(file_id, deadline);
+            }
+            result
+        })
+    }
+    
+    /// Record an access for eviction; persisted with the next index write.
+    ///
+    /// Skipped if the index can't be locked before `deadline`, since the data
+    /// has been served by then and only the eviction order is affected.
+    fn touch(&self, file_id: &str, deadline: Deadline) {
+        let mut file_index = match deadline.write(&self.file_index) {
+            Some(file_index) => file_index,
+            None => return,
         };
+        if let Some(file_info) = file_index.get_mut(file_id) {
+            file_info.last_access = Some(now_millis());
+            self.modified.store(true, Ordering::Release);
+        }
+    }
+    
+    fn write_parts(&self, file_info: &FileInfo, output_file: &File, hints: bool, deadline: Deadline) -> Result<()> {
+        let mut writer = output_file;
+        let mut source = hints.then(|| DropBehind::new(self.block_store.blocks_file()));
+        let mut output = hints.then(|| DropBehind::new(output_file));
+        let mut written = 0u64;
+        for part in file_info.parts() {
+            let block_data = self.read_part(part, deadline)?;
+            writer.write_all(&block_data)?;
+            
+            self.drop_behind_part(&mut source, part);
+            if let Some(output) = &mut output {
+                output.release(written, block_data.len() as u64);
+            }
+            written += block_data.len() as u64;
+        }
+        Ok(())
+    }
+    
+    /// Byte offset and size of each part in the reconstructed file.
+    ///
+    /// A packed file is a single part covering the whole file.
+    fn block_layout(&self, file_info: &FileInfo) -> Result<Vec<(u64, u64)>> {
+        let parts = file_info.parts();
+        let mut layout = Vec::with_capacity(parts.len());
+        let mut offset = 0u64;
+        for part in parts {
+            let size = match part {
+                FilePart::Block(hash) => self.block_store.block_info(hash)
+                    .ok_or_else(|| BlockError::BlockNotFound(hex::encode(hash)))?
+                    .size as u64,
+                FilePart::Packed(extent) => extent.len as u64,
+            };
+            layout.push((offset, size));
+            offset += size;
+        }
+        Ok(layout)
+    }
+    
+    fn get_block_layout(&self, file_id: &str) -> Result<Vec<(u64, u64, BlockHash)>> {
+        let file_info = self.lookup_file(file_id)?;
+        let layout = self.block_layout(&file_info)?;
+        Ok(layout.into_iter()
+            .zip(file_info.parts())
+            .map(|((offset, size), part)| match part {
+                FilePart::Block(hash) => (offset, size, *hash),
+                FilePart::Packed(extent) => (offset, size, extent.hash),
+            })
+            .collect())
+    }
+    
+    /// Write selected blocks and/or byte ranges of a file into `output_path`,
+    /// leaving everything else untouched.
+    ///
+    /// The output is created if needed and sized to the full file length, so
+    /// unwritten regions stay sparse. Existing content outside the requested
+    /// regions is preserved, which lets several calls (or processes) fill in
+    /// the same output out of order. Returns the number of bytes written.
+    ///
+    /// On timeout, regions already written are left in place; they hold
+    /// correct data and can simply be skipped on the next attempt.
+    fn retrieve_regions(
+        &self,
+        file_id: &str,
+        output_path: &Path,
+        block_indices: &[usize],
+        byte_ranges: &[(u64, u64)],
+        page_cache_hints: Option<bool>,
+        deadline: Deadline,
+    ) -> Result<u64> {
+        let hints = page_cache_hints.unwrap_or(self.page_cache_hints);
         
-        self.file_index.insert(file_id.to_string(), file_info);
-        self.modified = true;
-        self.save_index()?;
+        self.with_file_read_lock(file_id, deadline, || {
+            let file_info = self.lookup_for_read(file_id)?;
+            let layout = self.block_layout(&file_info)?;
+            
+            // Normalize everything to byte ranges within the file
+            let mut ranges = Vec::with_capacity(block_indices.len() + byte_ranges.len());
+            for &index in block_indices {
+                let &(offset, size) = layout.get(index).ok_or_else(|| CacheError::InvalidRange(
+                    format!("block index {} out of range for {} blocks", index, layout.len())))?;
+                ranges.push((offset, offset + size));
+            }
+            for &(start, end) in byte_ranges {
+                if start > end || end > file_info.size {
+                    return Err(CacheError::InvalidRange(
+                        format!("byte range {}..{} invalid for file of {} bytes", start, end, file_info.size)));
+                }
+                ranges.push((start, end));
+            }
+            
+            let output_file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(output_path)?;
+            if output_file.metadata()?.len() != file_info.size {
+                output_file.set_len(file_info.size)?;
+            }
+            let mut source = hints.then(|| DropBehind::new(self.block_store.blocks_file()));
+            let mut output = hints.then(|| DropBehind::new(&output_file));
+            
+            let parts = file_info.parts();
+            let mut written = 0u64;
+            for (start, end) in ranges {
+                // First block that ends after `start`
+                let first = layout.partition_point(|&(offset, size)| offset + size <= start);
+                
+                for (index, &(offset, size)) in layout.iter().enumerate().skip(first) {
+                    if offset >= end {
+                        break;
+                    }
+                    
+                    let part = parts[index];
+                    let block_data = self.read_part(part, deadline)?;
+                    
+                    let from = start.max(offset);
+                    let to = end.min(offset + size);
+                    let slice = &block_data[(from - offset) as usize..(to - offset) as usize];
+                    write_all_at(&output_file, slice, from)?;
+                    written += slice.len() as u64;
+                    
+                    self.drop_behind_part(&mut source, part);
+                    if let Some(output) = &mut output {
+                        output.release(from, slice.len() as u64);
+                    }
+                }
+            }
+            
+            self.touch(file_id, deadline);
+            Ok(written)
+        })
+    }
+    
+    /// Check every block of a file against its content hash, and the whole
+    /// file against its recorded digest (if any).
+    ///
+    /// On a mismatch the file is quarantined, along with every other file
+    /// that shares a corrupt block.
+    fn verify_file(&self, file_id: &str) -> Result<bool> {
+        self.with_file_read_lock(file_id, Deadline::NONE, || {
+            let (corrupt_blocks, digest_ok) = self.check_integrity(&self.lookup_file(file_id)?)?;
+            if corrupt_blocks.is_empty() && digest_ok {
+                return Ok(true);
+            }
+            
+            // Still under the entry lock, which a store of the same id needs
+            // exclusively, so its fresh entry can't be quarantined
+            self.quarantine(file_id, &corrupt_blocks)?;
+            Ok(false)
+        })
+    }
+    
+    /// Content hashes of the parts whose stored bytes no longer match or can't
+    /// be read back, and whether the whole-file digest still matches.
+    fn check_integrity(&self, file_info: &FileInfo) -> Result<(Vec<BlockHash>, bool)> {
+        let mut hasher = file_info.hash.as_ref()
+            .and(file_info.hash_algorithm)
+            .map(|algorithm| algorithm.hasher());
+        let mut corrupt_blocks = Vec::new();
         
-        Ok(())
+        for part in file_info.parts() {
+            // A truncated blocks file or a missing block is as corrupt as a bad hash
+            let data = match self.read_part(part, Deadline::NONE) {
+                Ok(data) => data,
+                Err(e) if e.is_timeout() => return Err(e),
+                Err(_) => {
+                    corrupt_blocks.push(*part.content_hash());
+                    continue;
+                }
+            };
+            if BlockStore::hash_block(&data) != *part.content_hash() {
+                corrupt_blocks.push(*part.content_hash());
+            }
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&data);
+            }
+        }
+        
+        let digest_ok = match (hasher, &file_info.hash) {
+            (Some(hasher), Some(expected)) => hasher.finalize() == *expected,
+            _ => true,
+        };
+        Ok((corrupt_blocks, digest_ok))
     }
     
-    fn retrieve_file(&mut self, file_id: &str, output_path: &Path) -> Result<()> {
-        let file_info = self.file_index.get(file_id)
-            .ok_or_else(|| CacheError::FileNotFound(file_id.to_string()))?;
+    /// Quarantine `file_id` and every file that references one of `corrupt_blocks`.
+    fn quarantine(&self, file_id: &str, corrupt_blocks: &[BlockHash]) -> Result<()> {
+        for hash in corrupt_blocks {
+            self.block_store.mark_corrupt(hash);
+        }
+        
+        let detected_at = now_millis();
+        for (id, file_info) in self.file_index.write().unwrap().iter_mut() {
+            let affected: Vec<String> = file_info.parts().iter()
+                .map(|part| part.content_hash())
+                .filter(|hash| corrupt_blocks.contains(hash))
+                .map(hex::encode)
+                .collect();
+            if affected.is_empty() && id != file_id {
+                continue;
+            }
             
-        let mut output_file = File::create(output_path)?;
+            let quarantine = file_info.quarantine.get_or_insert_with(|| Quarantine {
+                detected_at,
+                corrupt_blocks: Vec::new(),
+            });
+            for hash in affected {
+                if !quarantine.corrupt_blocks.contains(&hash) {
+                    quarantine.corrupt_blocks.push(hash);
+                }
+            }
+        }
+        
+        self.modified.store(true, Ordering::Release);
+        self.save_index(Deadline::NONE)
+    }
+    
+    fn list_quarantined(&self) -> Vec<(String, Quarantine)> {
+        let mut quarantined: Vec<(String, Quarantine)> = self.file_index.read().unwrap()
+            .iter()
+            .filter_map(|(id, info)| Some((id.clone(), info.quarantine.clone()?)))
+            .collect();
+        quarantined.sort_by(|a, b| a.0.cmp(&b.0));
+        quarantined
+    }
+    
+    /// Verify quarantined files again (all of them, or just `file_ids`) and
+    /// release the ones that now pass, e.g. after a shared block was repaired
+    /// by storing its content again. Returns whether each file passed.
+    fn reverify_quarantined(&self, file_ids: Option<&[String]>) -> Result<Vec<(String, bool)>> {
+        let file_ids = self.quarantined_ids(file_ids);
+        let mut results = Vec::with_capacity(file_ids.len());
         
-        for hash in &file_info.blocks {
-            let block_data = self.block_store.read_block(hash)?;
-            output_file.write_all(&block_data)?;
+        for file_id in file_ids {
+            let passed = self.with_file_lock(&file_id, Deadline::NONE, || {
+                let file_info = match self.lookup_file(&file_id) {
+                    Ok(file_info) => file_info,
+                    // Removed or stored again in the meantime
+                    Err(CacheError::FileNotFound(_)) => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                let (corrupt_blocks, digest_ok) = self.check_integrity(&file_info)?;
+                if !corrupt_blocks.is_empty() || !digest_ok {
+                    self.quarantine(&file_id, &corrupt_blocks)?;
+                    return Ok(Some(false));
+                }
+                
+                if let Some(info) = self.file_index.write().unwrap().get_mut(&file_id) {
+                    info.quarantine = None;
+                }
+                for part in file_info.parts() {
+                    self.block_store.clear_corrupt(part.content_hash());
+                }
+                self.modified.store(true, Ordering::Release);
+                Ok(Some(true))
+            })?;
+            
+            if let Some(passed) = passed {
+                results.push((file_id, passed));
+            }
         }
         
-        Ok(())
+        self.save_index(Deadline::NONE)?;
+        Ok(results)
     }
     
-    fn remove_file(&mut self, file_id: &str) -> Result<()> {
-        let file_info = self.file_index.remove(file_id)
-            .ok_or_else(|| CacheError::FileNotFound(file_id.to_string()))?;
+    /// Release quarantined files without verifying them, e.g. after repairing
+    /// the data by other means. Returns the ids that were released.
+    fn clear_quarantine(&self, file_ids: Option<&[String]>) -> Result<Vec<String>> {
+        let file_ids = self.quarantined_ids(file_ids);
+        let mut cleared = Vec::with_capacity(file_ids.len());
+        
+        {
+            let mut file_index = self.file_index.write().unwrap();
+            for file_id in file_ids {
+                if let Some(info) = file_index.get_mut(&file_id) {
+                    if info.quarantine.take().is_some() {
+                        cleared.push(file_id);
+                    }
+                }
+            }
             
-        // Decrement reference counts
-        for hash in &file_info.blocks {
-            self.block_store.decrement_ref(hash)?;
+            // Blocks no longer listed by any quarantined file stop being treated as corrupt
+            let still_corrupt: HashSet<&String> = file_index.values()
+                .filter_map(|info| info.quarantine.as_ref())
+                .flat_map(|quarantine| &quarantine.corrupt_blocks)
+                .collect();
+            for file_id in &cleared {
+                let hashes = file_index[file_id].parts().iter()
+                    .map(|part| *part.content_hash())
+                    .filter(|hash| !still_corrupt.contains(&hex::encode(hash)))
+                    .collect::<Vec<_>>();
+                for hash in hashes {
+                    self.block_store.clear_corrupt(&hash);
+                }
+            }
         }
         
-        self.modified = true;
-        self.save_index()?;
+        self.modified.store(true, Ordering::Release);
+        self.save_index(Deadline::NONE)?;
+        Ok(cleared)
+    }
+    
+    /// The quarantined subset of `file_ids`, or all quarantined ids.
+    fn quarantined_ids(&self, file_ids: Option<&[String]>) -> Vec<String> {
+        let file_index = self.file_index.read().unwrap();
+        match file_ids {
+            Some(file_ids) => file_ids.iter()
+                .filter(|id| file_index.get(*id).is_some_and(|info| info.quarantine.is_some()))
+                .cloned()
+                .collect(),
+            None => {
+                let mut ids: Vec<String> = file_index.iter()
+                    .filter(|(_, info)| info.quarantine.is_some())
+                    .map(|(id, _)| id.clone())
+                    .collect();
+                ids.sort();
+                ids
+            }
+        }
+    }
+    
+    fn remove_file(&self, file_id: &str, deadline: Deadline) -> Result<()> {
+        self.with_file_lock(file_id, deadline, || {
+            // Taken first, so a timeout leaves the entry in place
+            let mut saved_generation = self.lock_index_writes(deadline)?;
+            self.remove_entry(file_id, deadline)?;
+            self.write_index(&mut saved_generation)
+        })
+    }
+    
+    /// Drop an entry and its block references, without saving the index.
+    fn remove_entry(&self, file_id: &str, deadline: Deadline) -> Result<()> {
+        let mut file_index = self.file_index.write().unwrap();
+        let file_info = file_index.get(file_id)
+            .ok_or_else(|| CacheError::FileNotFound(file_id.to_string()))?;
         
+        // Decrement reference counts; the entry only goes once they are released
+        self.release_parts(file_info, deadline)?;
+        file_index.remove(file_id);
+        
+        self.modified.store(true, Ordering::Release);
         Ok(())
     }
     
+    /// Work out which files least-recently-used eviction would remove to free
+    /// at least `target_free_bytes`, without changing anything.
+    ///
+    /// Blocks shared with files that stay are not counted as freed, and a
+    /// packed member only counts once every file referencing it is evicted.
+    /// If the whole cache can't free the target, every file is listed.
+    fn plan_eviction(&self, target_free_bytes: u64) -> EvictionPlan {
+        let file_index = self.file_index.read().unwrap();
+        
+        // Remaining references and stored size of every block, updated as files are evicted
+        let mut blocks: HashMap<BlockHash, (u32, u64)> = self.block_store.get_index()
+            .into_iter()
+            .map(|(hash, info)| (hash, (info.ref_count, info.stored_size() as u64)))
+            .collect();
+        
+        // Same for packed members, by content hash
+        let mut members: HashMap<BlockHash, (u32, u64)> = HashMap::new();
+        for extent in file_index.values().filter_map(|info| info.packed.as_ref()) {
+            members.entry(extent.hash).or_insert((0, extent.len as u64)).0 += 1;
+        }
+        
+        let mut candidates: Vec<(&String, &FileInfo)> = file_index.iter().collect();
+        candidates.sort_by_key(|&(file_id, info)| (info.last_access, file_id));
+        
+        let mut plan = EvictionPlan {
+            file_ids: Vec::new(),
+            freed_bytes: 0,
+            logical_bytes: 0,
+        };
+        
+        for (file_id, file_info) in candidates {
+            if plan.freed_bytes >= target_free_bytes {
+                break;
+            }
+            
+            for part in file_info.parts() {
+                let remaining = match part {
+                    FilePart::Block(hash) => blocks.get_mut(hash),
+                    FilePart::Packed(extent) => members.get_mut(&extent.hash),
+                };
+                if let Some((refs, size)) = remaining {
+                    *refs = refs.saturating_sub(1);
+                    if *refs == 0 {
+                        plan.freed_bytes += *size;
+                    }
+                }
+            }
+            
+            plan.file_ids.push(file_id.clone());
+            plan.logical_bytes += file_info.size;
+        }
+        
+        plan
+    }
+    
+    /// Remove the files `plan_eviction` picks for `target_free_bytes`.
+    ///
+    /// Files removed concurrently in the meantime are skipped. The freed space
+    /// becomes dead space in the blocks file until the next compaction.
+    fn evict(&self, target_free_bytes: u64, deadline: Deadline) -> Result<EvictionPlan> {
+        let plan = self.plan_eviction(target_free_bytes);
+        
+        let result = plan.file_ids.iter().try_for_each(|file_id| {
+            match self.with_file_lock(file_id, deadline, || self.remove_entry(file_id, deadline)) {
+                Err(CacheError::FileNotFound(_)) => Ok(()),
+                other => other,
+            }
+        });
+        
+        // Persist the removals that happened, even if a later one failed. If
+        // this times out too, they are saved with the next index write.
+        self.save_index(deadline)?;
+        result.map(|()| plan)
+    }
+    
+    /// Import every regular file in a restic snapshot as `id_prefix` + its path.
+    ///
+    /// Contents are decrypted and streamed straight into the block store, so
+    /// nothing is restored to disk. Files imported before an error are kept.
+    fn import_restic(
+        &self,
+        repo_path: &Path,
+        password: &str,
+        snapshot: Option<&str>,
+        id_prefix: &str,
+    ) -> Result<ImportSummary> {
+        let repo = restic::Repository::open(repo_path, password)?;
+        let (snapshot_id, snapshot) = repo.find_snapshot(snapshot)?;
+        let (files, skipped) = repo.files(&snapshot.tree)?;
+        
+        let mut summary = ImportSummary {
+            snapshot: snapshot_id,
+            paths: snapshot.paths,
+            file_ids: Vec::with_capacity(files.len()),
+            bytes: 0,
+            skipped,
+        };
+        
+        let result = files.iter().try_for_each(|file| {
+            let file_id = format!("{}{}", id_prefix, file.path);
+            self.with_file_lock(&file_id, Deadline::NONE, || {
+                let mut file_info = FileInfo {
+                    blocks: Vec::new(),
+                    size: file.size,
+                    name: file.name.clone(),
+                    hash: None,
+                    hash_algorithm: Some(self.file_hash_algorithm),
+                    packed: None,
+                    last_access: Some(now_millis()),
+                    quarantine: None,
+                };
+                
+                let mut reader = repo.file_reader(file);
+                self.ingest_from(&mut reader, &mut file_info, false, Deadline::NONE, |_, _| {})?;
+                
+                // Past the ingest, so its references have to be given back here
+                let trailing = match reader.read(&mut [0u8; 1]) {
+                    Ok(0) => Ok(()),
+                    Ok(_) => Err(CacheError::Other(format!("{} has more content than its recorded size", file.path))),
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = trailing {
+                    let _ = self.release_parts(&file_info, Deadline::NONE);
+                    return Err(e);
+                }
+                
+                self.insert_entry(&file_id, file_info, Deadline::NONE)
+            })?;
+            
+            summary.bytes += file.size;
+            summary.file_ids.push(file_id);
+            Ok(())
+        });
+        
+        // Persist whatever was imported, even if a later file failed
+        self.save_index(Deadline::NONE)?;
+        result.map(|()| summary)
+    }
+    
     fn get_stats(&self) -> (usize, usize, u64, u64) {
+        let file_index = self.file_index.read().unwrap();
+        
         let total_blocks = self.block_store.block_count();
-        let total_files = self.file_index.len();
+        let total_files = file_index.len();
         
         let stored_size = self.block_store.total_size();
-            
-        let logical_size: u64 = self.file_index.values()
+        
+        let logical_size: u64 = file_index.values()
             .map(|info| info.size)
             .sum();
-            
+        
         (total_blocks, total_files, stored_size, logical_size)
     }
+    
+    /// Time since the last operation finished, or `None` while one is running.
+    fn idle_time(&self) -> Option<Duration> {
+        let activity = self.activity.lock().unwrap();
+        if activity.active_ops > 0 {
+            None
+        } else {
+            Some(activity.last_finished.elapsed())
+        }
+    }
+    
+    fn compact(&self, trigger: CompactionTrigger) -> Result<CompactionOutcome> {
+        if !self.compaction.begin(trigger) {
+            return Err(CacheError::Other("Compaction already running".to_string()));
+        }
+        
+        // An automatic run stops, as if cancelled, once the maintenance window closes
+        let deadline = match trigger {
+            CompactionTrigger::Automatic => Deadline::after(
+                self.maintenance_policy.lock().unwrap().window_remaining(SystemTime::now()),
+            ),
+            CompactionTrigger::Manual => Deadline::NONE,
+        };
+        
+        let result = self.compact_blocks(deadline);
+        self.compaction.finish(&result.as_ref().map(|o| *o).map_err(|e| e.to_string()));
+        result
+    }
+    
+    fn compact_blocks(&self, deadline: Deadline) -> Result<CompactionOutcome> {
+        let cancelled = || {
+            let file_size = self.block_store.file_size();
+            Ok(CompactionOutcome {
+                bytes_before: file_size,
+                bytes_after: file_size,
+                cancelled: true,
+                ..Default::default()
+            })
+        };
+        
+        let copy = self.block_store.copy_live(self.compaction.cancel_flag(), deadline, |copied, total| {
+            self.compaction.progress(copied, total)
+        })?;
+        let copy = match copy {
+            Some(copy) => copy,
+            None => return cancelled(),
+        };
+        
+        // Held until the file index has the new member extents, so no index
+        // naming the new blocks file is saved with members at their old offsets
+        let mut saved_generation = match deadline.lock(&self.index_write_lock) {
+            Some(saved_generation) => saved_generation,
+            None => return cancelled(),
+        };
+        let outcome = copy.switch()?;
+        
+        // Switch the index over to the new blocks file; until this succeeds
+        // the index on disk still matches the old one
+        let extents = self.block_store.member_extents();
+        for file_info in self.file_index.write().unwrap().values_mut() {
+            if let Some(extent) = &mut file_info.packed {
+                if let Some(current) = extents.get(&extent.hash) {
+                    *extent = *current;
+                }
+            }
+        }
+        self.write_index(&mut saved_generation)?;
+        
+        Ok(outcome)
+    }
+    
+    /// Run an automatic compaction if the maintenance policy currently allows it.
+    fn run_scheduled_maintenance(&self) -> Result<Option<CompactionOutcome>> {
+        let policy = self.maintenance_policy.lock().unwrap().clone();
+        let due = policy.should_compact(
+            self.block_store.dead_bytes(),
+            self.block_store.file_size(),
+            self.idle_time(),
+            SystemTime::now(),
+        );
+        
+        if !due || self.compaction.status().running {
+            return Ok(None);
+        }
+        
+        self.compact(CompactionTrigger::Automatic).map(Some)
+    }
+    
+    fn set_maintenance_policy(self: &Arc<Self>, policy: MaintenancePolicy) {
+        let enabled = policy.enabled;
+        *self.maintenance_policy.lock().unwrap() = policy;
+        
+        if enabled && !self.maintenance_started.swap(true, Ordering::AcqRel) {
+            spawn_maintenance_thread(Arc::downgrade(self));
+        }
+    }
+    
+    fn get_compression_stats(&self) -> HashMap<Compression, CompressionTotals> {
+        self.block_store.compression_totals()
+    }
+    
+    fn get_file_info(&self, file_id: &str) -> Result<FileStats> {
+        let file_info = self.lookup_file(file_id)?;
+        
+        let mut compression: HashMap<Compression, CompressionTotals> = HashMap::new();
+        for part in file_info.parts() {
+            match part {
+                FilePart::Block(hash) => {
+                    let block_info = self.block_store.block_info(hash)
+                        .ok_or_else(|| BlockError::BlockNotFound(hex::encode(hash)))?;
+                    compression.entry(block_info.compression).or_default().add(&block_info);
+                }
+                FilePart::Packed(extent) => {
+                    let pack_info = self.block_store.block_info(&extent.pack)
+                        .ok_or_else(|| BlockError::BlockNotFound(hex::encode(extent.pack)))?;
+                    let totals = compression.entry(pack_info.compression).or_default();
+                    totals.blocks += 1;
+                    totals.raw_bytes += extent.len as u64;
+                    totals.stored_bytes += extent.len as u64;
+                }
+            }
+        }
+        
+        let stored_size = compression.values().map(|t| t.stored_bytes).sum();
+        
+        Ok(FileStats {
+            block_count: file_info.parts().len(),
+            name: file_info.name,
+            size: file_info.size,
+            packed: file_info.packed.is_some(),
+            stored_size,
+            compression,
+            hash: file_info.hash,
+            hash_algorithm: file_info.hash_algorithm,
+            last_access: file_info.last_access,
+            quarantined: file_info.quarantine.is_some(),
+        })
+    }
+}
+
+/// Background thread that periodically checks the maintenance policy.
+///
+/// Holds only a weak reference so it exits once the cache is dropped.
+fn spawn_maintenance_thread(storage: Weak<CacheStorage>) {
+    std::thread::spawn(move || {
+        let mut since_check = Duration::ZERO;
+        loop {
+            let tick = Duration::from_secs(1);
+            std::thread::sleep(tick);
+            since_check += tick;
+            
+            let storage = match storage.upgrade() {
+                Some(storage) => storage,
+                None => return,
+            };
+            
+            let interval = storage.maintenance_policy.lock().unwrap().check_interval_seconds;
+            if since_check < Duration::from_secs(interval) {
+                continue;
+            }
+            since_check = Duration::ZERO;
+            
+            // Failures are reported through compaction_status()
+            let _ = storage.run_scheduled_maintenance();
+        }
+    });
+}
+
+/// Make a rename inside `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn decode_block_hash(hex: &str) -> Option<BlockHash> {
+    hex::decode(hex).ok()?.try_into().ok()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn epoch_secs(time: Option<SystemTime>) -> Option<f64> {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs_f64())
+}
+
+fn compaction_outcome_dict<'py>(py: Python<'py>, outcome: &CompactionOutcome) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("blocks_moved", outcome.blocks_moved)?;
+    dict.set_item("bytes_before", outcome.bytes_before)?;
+    dict.set_item("bytes_after", outcome.bytes_after)?;
+    dict.set_item("bytes_reclaimed", outcome.bytes_before - outcome.bytes_after)?;
+    dict.set_item("cancelled", outcome.cancelled)?;
+    Ok(dict)
+}
+
+fn compaction_status_dict<'py>(
+    py: Python<'py>,
+    status: &CompactionStatus,
+    dead_bytes: u64,
+    file_size: u64,
+) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("running", status.running)?;
+    dict.set_item("trigger", status.trigger.map(|t| t.as_str()))?;
+    dict.set_item("started_at", epoch_secs(status.started_at))?;
+    dict.set_item("bytes_copied", status.bytes_copied)?;
+    dict.set_item("bytes_total", status.bytes_total)?;
+    dict.set_item("dead_bytes", dead_bytes)?;
+    dict.set_item("blocks_file_bytes", file_size)?;
+    dict.set_item("last_finished_at", epoch_secs(status.last_finished_at))?;
+    let last_outcome = match &status.last_outcome {
+        Some(outcome) => Some(compaction_outcome_dict(py, outcome)?),
+        None => None,
+    };
+    dict.set_item("last_outcome", last_outcome)?;
+    dict.set_item("last_error", status.last_error.as_deref())?;
+    Ok(dict)
+}
+
+fn compression_dict<'py>(
+    py: Python<'py>,
+    totals: &HashMap<Compression, CompressionTotals>,
+) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    for (compression, t) in totals {
+        let entry = PyDict::new(py);
+        entry.set_item("blocks", t.blocks)?;
+        entry.set_item("raw_bytes", t.raw_bytes)?;
+        entry.set_item("stored_bytes", t.stored_bytes)?;
+        dict.set_item(compression.as_str(), entry)?;
+    }
+    Ok(dict)
+}
+
+fn eviction_plan_dict(py: Python<'_>, plan: EvictionPlan, target_free_bytes: u64) -> PyResult<&PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("target_met", plan.freed_bytes >= target_free_bytes)?;
+    dict.set_item("file_ids", plan.file_ids)?;
+    dict.set_item("freed_bytes", plan.freed_bytes)?;
+    dict.set_item("logical_bytes", plan.logical_bytes)?;
+    Ok(dict)
 }
 
 #[pyclass]
 struct Cache {
-    storage: Arc<Mutex<CacheStorage>>,
+    storage: Arc<CacheStorage>,
+    /// Cache-wide default for store/retrieve/remove timeouts, in seconds.
+    timeout: Option<f64>,
+}
+
+impl Cache {
+    fn deadline(&self, timeout: Option<f64>) -> PyResult<Deadline> {
+        let timeout = match timeout.or(self.timeout) {
+            Some(secs) => Some(Duration::try_from_secs_f64(secs)
+                .map_err(|_| PyValueError::new_err(format!("Invalid timeout: {}", secs)))?),
+            None => None,
+        };
+        Ok(Deadline::after(timeout))
+    }
 }
 
 #[pymethods]
 impl Cache {
+    /// `small_file_threshold`: files smaller than this many bytes are packed
+    /// together into shared pack blocks (0 disables packing).
+    ///
+    /// `timeout`: default limit in seconds for store, retrieve and remove,
+    /// including time spent waiting for locks. Operations that run out of time
+    /// raise `TimeoutError` and roll back any partial state. `None` waits forever.
+    /// A single hung read or write can't be interrupted; the operation gives up
+    /// once it returns.
     #[new]
-    fn new(block_size: usize, cache_dir: &str) -> PyResult<Self> {
-        let storage = CacheStorage::new(block_size, Path::new(cache_dir))
-            .map_err(|e| PyIOError::new_err(e.to_string()))?;
-            
-        Ok(Cache {
-            storage: Arc::new(Mutex::new(storage)),
-        })
+    #[pyo3(signature = (
+        block_size,
+        cache_dir,
+        file_hash_algorithm = "blake3",
+        page_cache_hints = false,
+        small_file_threshold = 0,
+        timeout = None,
+    ))]
+    fn new(
+        block_size: usize,
+        cache_dir: &str,
+        file_hash_algorithm: &str,
+        page_cache_hints: bool,
+        small_file_threshold: usize,
+        timeout: Option<f64>,
+    ) -> PyResult<Self> {
+        let file_hash_algorithm: FileHashAlgorithm = file_hash_algorithm.parse()
+            .map_err(PyValueError::new_err)?;
+        
+        let storage = CacheStorage::new(
+            block_size,
+            Path::new(cache_dir),
+            file_hash_algorithm,
+            page_cache_hints,
+            small_file_threshold,
+        )
+            .map_err(to_py_err)?;
+        
+        let cache = Cache {
+            storage: Arc::new(storage),
+            timeout,
+        };
+        // Validate the default up front rather than on first use
+        cache.deadline(None)?;
+        Ok(cache)
     }
     
-    fn store_file(&self, file_path: &str, file_id: Option<&str>) -> PyResult<String> {
+    /// `page_cache_hints` and `timeout` override the cache-wide settings for this call.
+    #[pyo3(signature = (file_path, file_id = None, page_cache_hints = None, timeout = None))]
+    fn store_file(
+        &self,
+        py: Python<'_>,
+        file_path: &str,
+        file_id: Option<&str>,
+        page_cache_hints: Option<bool>,
+        timeout: Option<f64>,
+    ) -> PyResult<String> {
+        let deadline = self.deadline(timeout)?;
         let file_id = file_id.map_or_else(
             || {
                 // Generate a file ID based on path if not provided
@@ -230,37 +1501,775 @@ impl Cache {
             |id| id.to_string(),
         );
         
-        let mut storage = self.storage.lock().unwrap();
-        storage.store_file(Path::new(file_path), &file_id)
-            .map_err(|e| PyIOError::new_err(e.to_string()))?;
-            
+        py.allow_threads(|| self.storage.store_file(Path::new(file_path), &file_id, page_cache_hints, deadline))
+            .map_err(to_py_err)?;
+        
         Ok(file_id)
     }
     
-    fn retrieve_file(&self, file_id: &str, output_path: &str) -> PyResult<()> {
-        let mut storage = self.storage.lock().unwrap();
-        storage.retrieve_file(file_id, Path::new(output_path))
-            .map_err(|e| PyIOError::new_err(e.to_string()))?;
-            
+    /// `page_cache_hints` and `timeout` override the cache-wide settings for this call.
+    #[pyo3(signature = (file_id, output_path, page_cache_hints = None, timeout = None))]
+    fn retrieve_file(
+        &self,
+        py: Python<'_>,
+        file_id: &str,
+        output_path: &str,
+        page_cache_hints: Option<bool>,
+        timeout: Option<f64>,
+    ) -> PyResult<()> {
+        let deadline = self.deadline(timeout)?;
+        py.allow_threads(|| self.storage.retrieve_file(file_id, Path::new(output_path), page_cache_hints, deadline))
+            .map_err(to_py_err)?;
+        
         Ok(())
     }
     
-    fn remove_file(&self, file_id: &str) -> PyResult<()> {
-        let mut storage = self.storage.lock().unwrap();
-        storage.remove_file(file_id)
-            .map_err(|e| PyIOError::new_err(e.to_string()))?;
-            
+    /// Write only the given blocks and/or `(start, end)` byte ranges of a file
+    /// into a pre-sized sparse output, preserving whatever else it contains.
+    #[pyo3(signature = (
+        file_id,
+        output_path,
+        block_indices = None,
+        byte_ranges = None,
+        page_cache_hints = None,
+        timeout = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn retrieve_regions(
+        &self,
+        py: Python<'_>,
+        file_id: &str,
+        output_path: &str,
+        block_indices: Option<Vec<usize>>,
+        byte_ranges: Option<Vec<(u64, u64)>>,
+        page_cache_hints: Option<bool>,
+        timeout: Option<f64>,
+    ) -> PyResult<u64> {
+        let deadline = self.deadline(timeout)?;
+        let block_indices = block_indices.unwrap_or_default();
+        let byte_ranges = byte_ranges.unwrap_or_default();
+        
+        py.allow_threads(|| self.storage.retrieve_regions(
+            file_id,
+            Path::new(output_path),
+            &block_indices,
+            &byte_ranges,
+            page_cache_hints,
+            deadline,
+        ))
+        .map_err(to_py_err)
+    }
+    
+    /// `(offset, size, block_hash)` for each block, in file order.
+    fn get_block_layout(&self, py: Python<'_>, file_id: &str) -> PyResult<Vec<(u64, u64, String)>> {
+        let layout = py.allow_threads(|| self.storage.get_block_layout(file_id))
+            .map_err(to_py_err)?;
+        Ok(layout.into_iter()
+            .map(|(offset, size, hash)| (offset, size, hex::encode(hash)))
+            .collect())
+    }
+    
+    /// Returns False if any block or the whole-file digest no longer matches,
+    /// in which case the file and every file sharing a corrupt block are
+    /// quarantined and `retrieve_file` raises `CorruptionError` for them.
+    fn verify_file(&self, py: Python<'_>, file_id: &str) -> PyResult<bool> {
+        py.allow_threads(|| self.storage.verify_file(file_id))
+            .map_err(to_py_err)
+    }
+    
+    #[pyo3(signature = (file_id, timeout = None))]
+    fn remove_file(&self, py: Python<'_>, file_id: &str, timeout: Option<f64>) -> PyResult<()> {
+        let deadline = self.deadline(timeout)?;
+        py.allow_threads(|| self.storage.remove_file(file_id, deadline))
+            .map_err(to_py_err)?;
+        
+        Ok(())
+    }
+    
+    /// Quarantined files as `{file_id: {"detected_at", "corrupt_blocks"}}`.
+    fn list_quarantined<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        for (file_id, quarantine) in self.storage.list_quarantined() {
+            let entry = PyDict::new(py);
+            entry.set_item("detected_at", quarantine.detected_at as f64 / 1000.0)?;
+            entry.set_item("corrupt_blocks", quarantine.corrupt_blocks)?;
+            dict.set_item(file_id, entry)?;
+        }
+        Ok(dict)
+    }
+    
+    /// Verify quarantined files again and release those that pass. Returns
+    /// `{file_id: passed}`; `file_ids` defaults to every quarantined file.
+    #[pyo3(signature = (file_ids = None))]
+    fn reverify_quarantined<'py>(&self, py: Python<'py>, file_ids: Option<Vec<String>>) -> PyResult<&'py PyDict> {
+        let results = py.allow_threads(|| self.storage.reverify_quarantined(file_ids.as_deref()))
+            .map_err(to_py_err)?;
+        let dict = PyDict::new(py);
+        for (file_id, passed) in results {
+            dict.set_item(file_id, passed)?;
+        }
+        Ok(dict)
+    }
+    
+    /// Release quarantined files without verifying them; returns the released ids.
+    #[pyo3(signature = (file_ids = None))]
+    fn clear_quarantine(&self, py: Python<'_>, file_ids: Option<Vec<String>>) -> PyResult<Vec<String>> {
+        py.allow_threads(|| self.storage.clear_quarantine(file_ids.as_deref()))
+            .map_err(to_py_err)
+    }
+    
+    /// Rewrite the blocks file without unreferenced space.
+    ///
+    /// Other operations carry on while it copies, and only wait for the final
+    /// switch to the new file.
+    fn compact<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let outcome = py.allow_threads(|| self.storage.compact(CompactionTrigger::Manual))
+            .map_err(to_py_err)?;
+        compaction_outcome_dict(py, &outcome)
+    }
+    
+    /// Doesn't wait for a running compaction, so it can be polled while one runs.
+    fn compaction_status<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        compaction_status_dict(
+            py,
+            &self.storage.compaction.status(),
+            self.storage.block_store.dead_bytes(),
+            self.storage.block_store.file_size(),
+        )
+    }
+    
+    /// Ask a running compaction to stop; returns False if none was running.
+    fn cancel_compaction(&self) -> bool {
+        self.storage.compaction.cancel()
+    }
+    
+    /// Configure when automatic compaction runs. Window hours are UTC.
+    #[pyo3(signature = (
+        enabled = true,
+        dead_bytes_threshold = 256 * 1024 * 1024,
+        dead_ratio_threshold = 0.0,
+        idle_seconds = 60,
+        window_start_hour = None,
+        window_end_hour = None,
+        check_interval_seconds = 60,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn set_maintenance_policy(
+        &self,
+        enabled: bool,
+        dead_bytes_threshold: u64,
+        dead_ratio_threshold: f64,
+        idle_seconds: u64,
+        window_start_hour: Option<u8>,
+        window_end_hour: Option<u8>,
+        check_interval_seconds: u64,
+    ) -> PyResult<()> {
+        let policy = MaintenancePolicy {
+            enabled,
+            dead_bytes_threshold,
+            dead_ratio_threshold,
+            idle_seconds,
+            window_start_hour,
+            window_end_hour,
+            check_interval_seconds,
+        };
+        policy.validate().map_err(PyValueError::new_err)?;
+        
+        self.storage.set_maintenance_policy(policy);
         Ok(())
     }
     
-    fn get_stats(&self) -> PyResult<(usize, usize, u64, u64)> {
-        let storage = self.storage.lock().unwrap();
-        Ok(storage.get_stats())
+    fn get_maintenance_policy<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let policy = self.storage.maintenance_policy.lock().unwrap().clone();
+        
+        let dict = PyDict::new(py);
+        dict.set_item("enabled", policy.enabled)?;
+        dict.set_item("dead_bytes_threshold", policy.dead_bytes_threshold)?;
+        dict.set_item("dead_ratio_threshold", policy.dead_ratio_threshold)?;
+        dict.set_item("idle_seconds", policy.idle_seconds)?;
+        dict.set_item("window_start_hour", policy.window_start_hour)?;
+        dict.set_item("window_end_hour", policy.window_end_hour)?;
+        dict.set_item("check_interval_seconds", policy.check_interval_seconds)?;
+        Ok(dict)
+    }
+    
+    /// Files that `evict(target_free_bytes)` would remove, least recently used
+    /// first, and the stored bytes that removing them would actually free.
+    /// Nothing is deleted.
+    fn plan_eviction<'py>(&self, py: Python<'py>, target_free_bytes: u64) -> PyResult<&'py PyDict> {
+        let plan = py.allow_threads(|| self.storage.plan_eviction(target_free_bytes));
+        eviction_plan_dict(py, plan, target_free_bytes)
+    }
+    
+    /// Remove least recently used files until `target_free_bytes` of stored
+    /// data is unreferenced. The space is reclaimed from the blocks file by
+    /// the next compaction.
+    #[pyo3(signature = (target_free_bytes, timeout = None))]
+    fn evict<'py>(&self, py: Python<'py>, target_free_bytes: u64, timeout: Option<f64>) -> PyResult<&'py PyDict> {
+        let deadline = self.deadline(timeout)?;
+        let plan = py.allow_threads(|| self.storage.evict(target_free_bytes, deadline))
+            .map_err(to_py_err)?;
+        eviction_plan_dict(py, plan, target_free_bytes)
+    }
+    
+    /// Import a snapshot from a restic repository without restoring it to disk.
+    ///
+    /// `snapshot` is an id prefix or "latest". Each regular file becomes an
+    /// entry named `id_prefix` + its path within the snapshot.
+    #[pyo3(signature = (repo_path, password, snapshot = None, id_prefix = ""))]
+    fn import_restic<'py>(
+        &self,
+        py: Python<'py>,
+        repo_path: &str,
+        password: &str,
+        snapshot: Option<&str>,
+        id_prefix: &str,
+    ) -> PyResult<&'py PyDict> {
+        let summary = py.allow_threads(|| self.storage.import_restic(Path::new(repo_path), password, snapshot, id_prefix))
+            .map_err(to_py_err)?;
+        
+        let dict = PyDict::new(py);
+        dict.set_item("snapshot", summary.snapshot)?;
+        dict.set_item("paths", summary.paths)?;
+        dict.set_item("file_ids", summary.file_ids)?;
+        dict.set_item("bytes", summary.bytes)?;
+        dict.set_item("skipped", summary.skipped)?;
+        Ok(dict)
+    }
+    
+    fn get_stats(&self, py: Python<'_>) -> PyResult<(usize, usize, u64, u64)> {
+        Ok(py.allow_threads(|| self.storage.get_stats()))
+    }
+    
+    /// Per-algorithm block counts and raw/stored byte totals for unique blocks.
+    fn get_compression_stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let totals = py.allow_threads(|| self.storage.get_compression_stats());
+        compression_dict(py, &totals)
+    }
+    
+    fn get_file_info<'py>(&self, py: Python<'py>, file_id: &str) -> PyResult<&'py PyDict> {
+        let stats = py.allow_threads(|| self.storage.get_file_info(file_id))
+            .map_err(to_py_err)?;
+        
+        let dict = PyDict::new(py);
+        dict.set_item("name", stats.name)?;
+        dict.set_item("size", stats.size)?;
+        dict.set_item("blocks", stats.block_count)?;
+        dict.set_item("packed", stats.packed)?;
+        dict.set_item("raw_bytes", stats.size)?;
+        dict.set_item("stored_bytes", stats.stored_size)?;
+        dict.set_item("compression", compression_dict(py, &stats.compression)?)?;
+        dict.set_item("hash", stats.hash)?;
+        dict.set_item("hash_algorithm", stats.hash_algorithm.map(|a| a.as_str()))?;
+        dict.set_item("last_access", stats.last_access.map(|ms| ms as f64 / 1000.0))?;
+        dict.set_item("quarantined", stats.quarantined)?;
+        Ok(dict)
     }
 }
 
 #[pymodule]
-fn unicache_rs(_py: Python, m: &PyModule) -> PyResult<()> {
+fn unicache_rs(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<Cache>()?;
+    m.add("CorruptionError", py.get_type::<CorruptionError>())?;
     Ok(())
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    
+    const BLOCK: usize = 4096;
+    
+    fn open(dir: &Path) -> CacheStorage {
+        CacheStorage::new(BLOCK, dir, FileHashAlgorithm::default(), false, 0).unwrap()
+    }
+    
+    /// Write a file made of one full block per fill byte.
+    fn input(dir: &Path, name: &str, fills: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, fills.iter().flat_map(|&fill| vec![fill; BLOCK]).collect::<Vec<u8>>()).unwrap();
+        path
+    }
+    
+    fn block_of(fill: u8) -> BlockHash {
+        BlockStore::hash_block(&vec![fill; BLOCK])
+    }
+    
+    fn ref_counts(storage: &CacheStorage) -> HashMap<BlockHash, u32> {
+        storage.block_store.get_index().into_iter()
+            .map(|(hash, info)| (hash, info.ref_count))
+            .collect()
+    }
+    
+    /// Serves `data`, but once `stall_at` bytes are read, stalls until `deadline` passes.
+    struct Stalling {
+        data: Vec<u8>,
+        pos: usize,
+        stall_at: usize,
+        deadline: Deadline,
+    }
+    
+    impl Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.pos >= self.stall_at && !self.deadline.expired() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let len = buf.len().min(self.data.len() - self.pos);
+            buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+    
+    #[test]
+    fn import_restic_fixtures() {
+        // The hand-written repositories, plus any generated with a real restic
+        let mut fixtures: Vec<PathBuf> = fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/restic"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.join("expected.json").exists())
+            .collect();
+        fixtures.sort();
+        assert!(fixtures.len() >= 2);
+        
+        for fixture in fixtures {
+            let version = fixture.file_name().unwrap().to_string_lossy().into_owned();
+            let expected: serde_json::Value = serde_json::from_slice(&fs::read(fixture.join("expected.json")).unwrap()).unwrap();
+            let files = expected["files"].as_object().unwrap();
+            
+            let dir = tempfile::tempdir().unwrap();
+            let storage = open(&dir.path().join("cache"));
+            let summary = storage.import_restic(&fixture.join("repo"), "secret", None, "bk:").unwrap();
+            
+            assert_eq!(summary.snapshot, expected["snapshot"].as_str().unwrap());
+            assert_eq!(serde_json::json!(summary.paths), expected["paths"]);
+            assert_eq!(summary.skipped, expected["skipped"].as_u64().unwrap() as usize);
+            
+            let mut file_ids = summary.file_ids.clone();
+            file_ids.sort();
+            let expected_ids: Vec<String> = files.keys().map(|path| format!("bk:{}", path)).collect();
+            assert_eq!(file_ids, expected_ids);
+            
+            let output = dir.path().join("out");
+            for (path, digest) in files {
+                storage.retrieve_file(&format!("bk:{}", path), &output, None, Deadline::NONE).unwrap();
+                let actual = hex::encode(Sha256::digest(fs::read(&output).unwrap()));
+                assert_eq!(actual, digest.as_str().unwrap(), "{} {}", version, path);
+            }
+        }
+    }
+    
+    #[test]
+    fn corrupt_shared_block_quarantines_every_file_until_it_is_stored_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        let storage = open(&cache_dir);
+        storage.store_file(&input(dir.path(), "a", &[1, 2]), "a", None, Deadline::NONE).unwrap();
+        storage.store_file(&input(dir.path(), "b", &[2, 3]), "b", None, Deadline::NONE).unwrap();
+        storage.store_file(&input(dir.path(), "c", &[3]), "c", None, Deadline::NONE).unwrap();
+        
+        // Flip a byte inside the block shared by "a" and "b"
+        let (offset, _) = storage.block_store.stored_range(&block_of(2)).unwrap();
+        write_all_at(&storage.block_store.blocks_file(), &[0xff], offset + 100).unwrap();
+        
+        assert!(!storage.verify_file("a").unwrap());
+        let quarantined: Vec<String> = storage.list_quarantined().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(quarantined, ["a", "b"]);
+        drop(storage);
+        
+        let storage = open(&cache_dir);
+        let output = dir.path().join("out");
+        for file_id in ["a", "b"] {
+            assert!(matches!(
+                storage.retrieve_file(file_id, &output, None, Deadline::NONE),
+                Err(CacheError::Quarantined(_))
+            ));
+        }
+        storage.retrieve_file("c", &output, None, Deadline::NONE).unwrap();
+        assert_eq!(storage.reverify_quarantined(None).unwrap(), [("a".to_string(), false), ("b".to_string(), false)]);
+        
+        // Storing the block's content again rewrites it in place
+        storage.store_file(&input(dir.path(), "d", &[2]), "d", None, Deadline::NONE).unwrap();
+        assert_eq!(storage.reverify_quarantined(None).unwrap(), [("a".to_string(), true), ("b".to_string(), true)]);
+        assert!(storage.list_quarantined().is_empty());
+        
+        storage.retrieve_file("b", &output, None, Deadline::NONE).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), fs::read(dir.path().join("b")).unwrap());
+    }
+    
+    #[test]
+    fn eviction_frees_exactly_what_was_planned() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = CacheStorage::new(BLOCK, &dir.path().join("cache"), FileHashAlgorithm::default(), false, 1024).unwrap();
+        let store = |file_id: &str, content: &[u8]| {
+            let path = dir.path().join(file_id);
+            fs::write(&path, content).unwrap();
+            storage.store_file(&path, file_id, None, Deadline::NONE).unwrap();
+        };
+        
+        // "a" and "b" share a block; "s1".."s3" are packed, and "s4" shares the member of "s1"
+        store("a", &[[1u8; BLOCK], [2u8; BLOCK]].concat());
+        store("b", &[[2u8; BLOCK], [3u8; BLOCK]].concat());
+        store("s1", &[4u8; 100]);
+        store("s2", &[5u8; 200]);
+        store("s3", &[6u8; 300]);
+        store("s4", &[4u8; 100]);
+        
+        for (order, file_id) in ["s1", "s2", "a", "s4", "b", "s3"].iter().enumerate() {
+            storage.file_index.write().unwrap().get_mut(*file_id).unwrap().last_access = Some(order as u64);
+        }
+        
+        // Neither the member shared with "s4" nor the block shared with "b" is freed
+        let plan = storage.plan_eviction(4200);
+        assert_eq!(plan.file_ids, ["s1", "s2", "a"]);
+        assert_eq!(plan.freed_bytes, 200 + BLOCK as u64);
+        
+        let live_before = storage.block_store.total_size();
+        let dead_before = storage.block_store.dead_bytes();
+        let evicted = storage.evict(4200, Deadline::NONE).unwrap();
+        assert_eq!(evicted.file_ids, plan.file_ids);
+        assert_eq!(live_before - storage.block_store.total_size(), plan.freed_bytes);
+        assert_eq!(storage.block_store.dead_bytes() - dead_before, plan.freed_bytes);
+        
+        let output = dir.path().join("out");
+        for file_id in ["s4", "b", "s3"] {
+            storage.retrieve_file(file_id, &output, None, Deadline::NONE).unwrap();
+            assert_eq!(fs::read(&output).unwrap(), fs::read(dir.path().join(file_id)).unwrap());
+        }
+        
+        // Retrieval refreshed their access times, so the order is no longer fixed
+        let mut plan = storage.plan_eviction(u64::MAX);
+        plan.file_ids.sort();
+        assert_eq!(plan.file_ids, ["b", "s3", "s4"]);
+        assert_eq!(plan.freed_bytes, 100 + 2 * BLOCK as u64 + 300);
+        storage.evict(u64::MAX, Deadline::NONE).unwrap();
+        assert_eq!(storage.block_store.total_size(), 0);
+        assert_eq!(storage.block_store.block_count(), 0);
+    }
+    
+    #[test]
+    fn store_timing_out_on_the_entry_lock_leaves_the_cache_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = &open(&dir.path().join("cache"));
+        let original = input(dir.path(), "x", &[1]);
+        storage.store_file(&original, "x", None, Deadline::NONE).unwrap();
+        let stats = storage.get_stats();
+        let refs = ref_counts(storage);
+        
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                storage.with_file_lock("x", Deadline::NONE, || {
+                    locked_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    Ok(())
+                })
+            });
+            locked_rx.recv().unwrap();
+            
+            let replacement = input(dir.path(), "y", &[2, 3]);
+            let result = storage.store_file(&replacement, "x", None, Deadline::after(Some(Duration::from_millis(50))));
+            assert!(result.unwrap_err().is_timeout());
+            release_tx.send(()).unwrap();
+        });
+        
+        assert_eq!(storage.get_stats(), stats);
+        assert_eq!(ref_counts(storage), refs);
+        
+        let output = dir.path().join("out");
+        storage.retrieve_file("x", &output, None, Deadline::NONE).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), fs::read(&original).unwrap());
+    }
+    
+    #[test]
+    fn store_timing_out_mid_ingest_gives_back_its_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir.path().join("cache"));
+        storage.store_file(&input(dir.path(), "base", &[1, 2]), "base", None, Deadline::NONE).unwrap();
+        let stats = storage.get_stats();
+        let refs = ref_counts(&storage);
+        
+        // Block n is filled with n % 256, so the first chunk both reuses the
+        // blocks of "base" and adds new ones before the read of the rest stalls
+        let chunk = 10 * 1024 * 1024;
+        let data: Vec<u8> = (0..chunk + BLOCK).map(|i| (i / BLOCK) as u8).collect();
+        let deadline = Deadline::after(Some(Duration::from_secs(1)));
+        let mut file_info = FileInfo {
+            blocks: Vec::new(),
+            size: data.len() as u64,
+            name: "stalling".to_string(),
+            hash: None,
+            hash_algorithm: Some(storage.file_hash_algorithm),
+            packed: None,
+            last_access: None,
+            quarantine: None,
+        };
+        let mut source = Stalling { data, pos: 0, stall_at: chunk, deadline };
+        
+        let result = storage.ingest_from(&mut source, &mut file_info, false, deadline, |_, _| {});
+        assert!(result.unwrap_err().is_timeout());
+        assert!(file_info.blocks.contains(&block_of(1)));
+        
+        assert_eq!(storage.get_stats(), stats);
+        assert_eq!(ref_counts(&storage), refs);
+    }
+    
+    #[test]
+    fn retrieve_timing_out_removes_the_output_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir.path().join("cache"));
+        storage.store_file(&input(dir.path(), "x", &[1, 2, 3]), "x", None, Deadline::NONE).unwrap();
+        
+        let output = dir.path().join("out");
+        let result = storage.retrieve_file("x", &output, None, Deadline::after(Some(Duration::ZERO)));
+        assert!(result.unwrap_err().is_timeout());
+        assert!(!output.exists());
+    }
+    
+    #[test]
+    fn compaction_drops_dead_pack_members_and_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        let storage = CacheStorage::new(BLOCK, &cache_dir, FileHashAlgorithm::default(), false, 1024).unwrap();
+        
+        let mut kept = Vec::new();
+        for i in 0..40u8 {
+            let file_id = format!("s{}", i);
+            let path = dir.path().join(&file_id);
+            fs::write(&path, vec![i; 100 + i as usize * 10]).unwrap();
+            storage.store_file(&path, &file_id, None, Deadline::NONE).unwrap();
+            if i % 3 == 0 {
+                storage.remove_file(&file_id, Deadline::NONE).unwrap();
+            } else {
+                kept.push((file_id, path));
+            }
+        }
+        
+        let live = storage.block_store.total_size();
+        assert!(storage.block_store.dead_bytes() > 0);
+        let outcome = storage.compact(CompactionTrigger::Manual).unwrap();
+        assert!(!outcome.cancelled);
+        assert_eq!(outcome.bytes_after, live);
+        assert_eq!(storage.block_store.dead_bytes(), 0);
+        drop(storage);
+        
+        let storage = CacheStorage::new(BLOCK, &cache_dir, FileHashAlgorithm::default(), false, 1024).unwrap();
+        assert_eq!(storage.block_store.dead_bytes(), 0);
+        let output = dir.path().join("out");
+        for (file_id, path) in &kept {
+            storage.retrieve_file(file_id, &output, None, Deadline::NONE).unwrap();
+            assert_eq!(fs::read(&output).unwrap(), fs::read(path).unwrap(), "{}", file_id);
+            assert!(storage.verify_file(file_id).unwrap());
+        }
+    }
+    
+    #[test]
+    fn store_and_remove_timing_out_on_the_index_lock_leave_the_cache_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir.path().join("cache"));
+        let original = input(dir.path(), "x", &[1, 2]);
+        storage.store_file(&original, "x", None, Deadline::NONE).unwrap();
+        let stats = storage.get_stats();
+        let refs = ref_counts(&storage);
+        let saved = fs::read(dir.path().join("cache").join("index.json")).unwrap();
+        
+        {
+            // As held by a running compaction
+            let _saved_generation = storage.index_write_lock.lock().unwrap();
+            let timeout = || Deadline::after(Some(Duration::from_millis(50)));
+            
+            let replacement = input(dir.path(), "y", &[2, 3]);
+            assert!(storage.store_file(&replacement, "x", None, timeout()).unwrap_err().is_timeout());
+            assert!(storage.store_file(&replacement, "y", None, timeout()).unwrap_err().is_timeout());
+            assert!(storage.remove_file("x", timeout()).unwrap_err().is_timeout());
+        }
+        
+        assert_eq!(storage.get_stats(), stats);
+        assert_eq!(ref_counts(&storage), refs);
+        assert_eq!(fs::read(dir.path().join("cache").join("index.json")).unwrap(), saved);
+        
+        let output = dir.path().join("out");
+        storage.retrieve_file("x", &output, None, Deadline::NONE).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), fs::read(&original).unwrap());
+    }
+    
+    #[test]
+    fn compaction_switches_generations_and_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        let storage = open(&cache_dir);
+        for (file_id, fills) in [("x", &[1, 2][..]), ("y", &[3]), ("z", &[2, 4, 5])] {
+            storage.store_file(&input(dir.path(), file_id, fills), file_id, None, Deadline::NONE).unwrap();
+        }
+        storage.remove_file("x", Deadline::NONE).unwrap();
+        let before = storage.block_store.get_index();
+        
+        let outcome = storage.compact(CompactionTrigger::Manual).unwrap();
+        assert!(!outcome.cancelled);
+        assert_eq!(outcome.bytes_before, 5 * BLOCK as u64);
+        assert_eq!(outcome.bytes_after, 4 * BLOCK as u64);
+        assert_eq!(outcome.blocks_moved, 4);
+        assert!(!cache_dir.join("blocks.bin").exists());
+        assert!(cache_dir.join("blocks.1.bin").exists());
+        
+        let after = storage.block_store.get_index();
+        assert!(after.iter().all(|(hash, info)| info.offset < before[hash].offset));
+        drop(storage);
+        
+        let storage = open(&cache_dir);
+        assert_eq!(storage.block_store.get_index().len(), 4);
+        assert_eq!(storage.block_store.dead_bytes(), 0);
+        let output = dir.path().join("out");
+        for file_id in ["y", "z"] {
+            storage.retrieve_file(file_id, &output, None, Deadline::NONE).unwrap();
+            assert_eq!(fs::read(&output).unwrap(), fs::read(dir.path().join(file_id)).unwrap());
+        }
+    }
+    
+    #[test]
+    fn truncated_blocks_file_quarantines_instead_of_failing() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir.path().join("cache"));
+        storage.store_file(&input(dir.path(), "a", &[1, 2]), "a", None, Deadline::NONE).unwrap();
+        storage.store_file(&input(dir.path(), "b", &[2, 3]), "b", None, Deadline::NONE).unwrap();
+        
+        // Cuts block 2 short and loses block 3 entirely
+        storage.block_store.blocks_file().set_len(BLOCK as u64 + 100).unwrap();
+        
+        assert!(!storage.verify_file("b").unwrap());
+        let quarantined = storage.list_quarantined();
+        assert_eq!(quarantined.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(quarantined[1].1.corrupt_blocks, [hex::encode(block_of(2)), hex::encode(block_of(3))]);
+        
+        storage.store_file(&input(dir.path(), "c", &[2, 3]), "c", None, Deadline::NONE).unwrap();
+        assert_eq!(storage.reverify_quarantined(None).unwrap(), [("a".to_string(), true), ("b".to_string(), true)]);
+    }
+    
+    #[test]
+    fn verification_quarantines_while_holding_the_entry_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = &open(&dir.path().join("cache"));
+        storage.store_file(&input(dir.path(), "x", &[1]), "x", None, Deadline::NONE).unwrap();
+        write_all_at(&storage.block_store.blocks_file(), &[0], 0).unwrap();
+        
+        std::thread::scope(|scope| {
+            // Stalls the verification in the index save that follows quarantining
+            let saved_generation = storage.index_write_lock.lock().unwrap();
+            scope.spawn(|| assert!(!storage.verify_file("x").unwrap()));
+            while storage.lookup_file("x").unwrap().quarantine.is_none() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            
+            // So a store of "x" can't slip in between the check and the quarantine
+            let locked = storage.with_file_lock("x", Deadline::after(Some(Duration::from_millis(50))), || Ok(()));
+            assert!(locked.unwrap_err().is_timeout());
+            drop(saved_generation);
+        });
+    }
+    
+    #[test]
+    fn retrieve_regions_writes_blocks_and_ranges_across_block_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir.path().join("cache"));
+        let original = input(dir.path(), "x", &[1, 2, 3]);
+        storage.store_file(&original, "x", None, Deadline::NONE).unwrap();
+        let content = fs::read(&original).unwrap();
+        
+        // Existing content outside the requested regions is kept
+        let output = dir.path().join("out");
+        fs::write(&output, vec![0xee; content.len()]).unwrap();
+        let (start, end) = (BLOCK as u64 + 100, 2 * BLOCK as u64 + 100);
+        let written = storage.retrieve_regions("x", &output, &[0], &[(start, end)], None, Deadline::NONE).unwrap();
+        assert_eq!(written, BLOCK as u64 + (end - start));
+        
+        let mut expected = vec![0xee; content.len()];
+        expected[..BLOCK].copy_from_slice(&content[..BLOCK]);
+        expected[start as usize..end as usize].copy_from_slice(&content[start as usize..end as usize]);
+        assert_eq!(fs::read(&output).unwrap(), expected);
+        
+        // Filling in the rest completes the file
+        let written = storage.retrieve_regions("x", &output, &[2], &[(BLOCK as u64, start)], None, Deadline::NONE).unwrap();
+        assert_eq!(written, BLOCK as u64 + 100);
+        assert_eq!(fs::read(&output).unwrap(), content);
+    }
+    
+    #[test]
+    fn retrieve_regions_of_a_packed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = CacheStorage::new(BLOCK, &dir.path().join("cache"), FileHashAlgorithm::default(), false, 1024).unwrap();
+        let original = dir.path().join("small");
+        let content: Vec<u8> = (0..500u32).map(|i| i as u8).collect();
+        fs::write(&original, &content).unwrap();
+        storage.store_file(&original, "small", None, Deadline::NONE).unwrap();
+        assert!(storage.lookup_file("small").unwrap().packed.is_some());
+        
+        let output = dir.path().join("out");
+        assert_eq!(storage.retrieve_regions("small", &output, &[], &[(100, 300)], None, Deadline::NONE).unwrap(), 200);
+        let mut expected = vec![0; content.len()];
+        expected[100..300].copy_from_slice(&content[100..300]);
+        assert_eq!(fs::read(&output).unwrap(), expected);
+        
+        assert_eq!(storage.retrieve_regions("small", &output, &[0], &[], None, Deadline::NONE).unwrap(), 500);
+        assert_eq!(fs::read(&output).unwrap(), content);
+    }
+    
+    #[test]
+    fn retrieve_regions_rejects_out_of_range_requests_before_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir.path().join("cache"));
+        storage.store_file(&input(dir.path(), "x", &[1, 2]), "x", None, Deadline::NONE).unwrap();
+        let size = 2 * BLOCK as u64;
+        
+        let output = dir.path().join("out");
+        for (block_indices, byte_ranges) in [
+            (&[2][..], &[][..]),
+            (&[], &[(0, size + 1)]),
+            (&[], &[(200, 100)]),
+            (&[0], &[(size, size + 1)]),
+        ] {
+            let result = storage.retrieve_regions("x", &output, block_indices, byte_ranges, None, Deadline::NONE);
+            assert!(matches!(result, Err(CacheError::InvalidRange(_))), "{:?} {:?}", block_indices, byte_ranges);
+            assert!(!output.exists());
+        }
+        
+        // An empty range at the very end is valid
+        assert_eq!(storage.retrieve_regions("x", &output, &[], &[(size, size)], None, Deadline::NONE).unwrap(), 0);
+    }
+    
+    #[test]
+    fn reads_share_the_entry_lock_that_stores_need_exclusively() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = &open(&dir.path().join("cache"));
+        let original = input(dir.path(), "x", &[1, 2]);
+        storage.store_file(&original, "x", None, Deadline::NONE).unwrap();
+        
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                storage.with_file_read_lock("x", Deadline::NONE, || {
+                    locked_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    Ok(())
+                })
+            });
+            locked_rx.recv().unwrap();
+            
+            // A reader waiting for a writer would time out here
+            let timeout = Deadline::after(Some(Duration::from_secs(5)));
+            let output = dir.path().join("out");
+            storage.retrieve_file("x", &output, None, timeout).unwrap();
+            assert_eq!(fs::read(&output).unwrap(), fs::read(&original).unwrap());
+            storage.retrieve_regions("x", &dir.path().join("part"), &[1], &[], None, timeout).unwrap();
+            assert!(storage.verify_file("x").unwrap());
+            
+            let timeout = Deadline::after(Some(Duration::from_millis(50)));
+            let replacement = input(dir.path(), "y", &[3]);
+            assert!(storage.store_file(&replacement, "x", None, timeout).unwrap_err().is_timeout());
+            assert!(storage.remove_file("x", timeout).unwrap_err().is_timeout());
+            release_tx.send(()).unwrap();
+        });
+    }
+}
@@ -0,0 +1,231 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::block::CompactionOutcome;
+
+/// When automatic compaction of the blocks file is allowed to run.
+///
+/// All enabled conditions must hold at the same time: enough dead bytes, no
+/// cache activity for `idle_seconds`, and the current UTC hour inside the
+/// window (if one is set).
+#[derive(Debug, Clone)]
+pub struct MaintenancePolicy {
+    pub enabled: bool,
+    /// Minimum unreferenced bytes in the blocks file.
+    pub dead_bytes_threshold: u64,
+    /// Minimum unreferenced fraction of the blocks file; 0 disables the check.
+    pub dead_ratio_threshold: f64,
+    /// Required time since the last cache operation; 0 disables the check.
+    pub idle_seconds: u64,
+    /// UTC hours `[start, end)`; wraps past midnight when start > end.
+    pub window_start_hour: Option<u8>,
+    pub window_end_hour: Option<u8>,
+    pub check_interval_seconds: u64,
+}
+
+impl Default for MaintenancePolicy {
+    fn default() -> Self {
+        MaintenancePolicy {
+            enabled: false,
+            dead_bytes_threshold: 256 * 1024 * 1024,
+            dead_ratio_threshold: 0.0,
+            idle_seconds: 60,
+            window_start_hour: None,
+            window_end_hour: None,
+            check_interval_seconds: 60,
+        }
+    }
+}
+
+impl MaintenancePolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        for hour in [self.window_start_hour, self.window_end_hour].into_iter().flatten() {
+            if hour > 23 {
+                return Err(format!("Window hour must be 0-23, got {}", hour));
+            }
+        }
+        if self.window_start_hour.is_some() != self.window_end_hour.is_some() {
+            return Err("Window start and end hours must be set together".to_string());
+        }
+        if self.window_start_hour.is_some() && self.window_start_hour == self.window_end_hour {
+            return Err("Window start and end hours must differ; leave both unset to allow any time".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.dead_ratio_threshold) {
+            return Err("Dead ratio threshold must be between 0 and 1".to_string());
+        }
+        if self.check_interval_seconds == 0 {
+            return Err("Check interval must be at least one second".to_string());
+        }
+        Ok(())
+    }
+    
+    pub fn in_window(&self, now: SystemTime) -> bool {
+        let (start, end) = match (self.window_start_hour, self.window_end_hour) {
+            (Some(start), Some(end)) => (start as u64, end as u64),
+            _ => return true,
+        };
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let hour = (secs / 3600) % 24;
+        if start <= end {
+            (start..end).contains(&hour)
+        } else {
+            hour >= start || hour < end
+        }
+    }
+    
+    /// Time until the window closes; zero outside it, `None` without a window.
+    pub fn window_remaining(&self, now: SystemTime) -> Option<Duration> {
+        let end = match (self.window_start_hour, self.window_end_hour) {
+            (Some(_), Some(end)) => end as u64,
+            _ => return None,
+        };
+        if !self.in_window(now) {
+            return Some(Duration::ZERO);
+        }
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let hours_left = (end + 24 - (secs / 3600) % 24) % 24;
+        Some(Duration::from_secs(hours_left * 3600 - secs % 3600))
+    }
+    
+    pub fn should_compact(&self, dead_bytes: u64, file_size: u64, idle: Option<Duration>, now: SystemTime) -> bool {
+        if !self.enabled || dead_bytes == 0 || dead_bytes < self.dead_bytes_threshold {
+            return false;
+        }
+        if self.dead_ratio_threshold > 0.0
+            && (dead_bytes as f64) < self.dead_ratio_threshold * file_size as f64
+        {
+            return false;
+        }
+        // `None` means an operation is in progress right now
+        if self.idle_seconds > 0 && idle.is_none_or(|idle| idle.as_secs() < self.idle_seconds) {
+            return false;
+        }
+        self.in_window(now)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionTrigger {
+    Manual,
+    Automatic,
+}
+
+impl CompactionTrigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompactionTrigger::Manual => "manual",
+            CompactionTrigger::Automatic => "automatic",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompactionStatus {
+    pub running: bool,
+    pub trigger: Option<CompactionTrigger>,
+    pub started_at: Option<SystemTime>,
+    pub bytes_copied: u64,
+    pub bytes_total: u64,
+    pub last_finished_at: Option<SystemTime>,
+    pub last_outcome: Option<CompactionOutcome>,
+    pub last_error: Option<String>,
+}
+
+/// Shared view of the current compaction, used to query and cancel it.
+#[derive(Default)]
+pub struct CompactionTracker {
+    status: Mutex<CompactionStatus>,
+    cancel: AtomicBool,
+}
+
+impl CompactionTracker {
+    /// Mark a compaction as started; false if one is already running.
+    pub fn begin(&self, trigger: CompactionTrigger) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if status.running {
+            return false;
+        }
+        self.cancel.store(false, Ordering::Relaxed);
+        status.running = true;
+        status.trigger = Some(trigger);
+        status.started_at = Some(SystemTime::now());
+        status.bytes_copied = 0;
+        status.bytes_total = 0;
+        true
+    }
+    
+    pub fn progress(&self, bytes_copied: u64, bytes_total: u64) {
+        let mut status = self.status.lock().unwrap();
+        status.bytes_copied = bytes_copied;
+        status.bytes_total = bytes_total;
+    }
+    
+    pub fn finish(&self, result: &Result<CompactionOutcome, String>) {
+        let mut status = self.status.lock().unwrap();
+        status.running = false;
+        status.last_finished_at = Some(SystemTime::now());
+        match result {
+            Ok(outcome) => {
+                status.last_outcome = Some(*outcome);
+                status.last_error = None;
+            }
+            Err(e) => status.last_error = Some(e.clone()),
+        }
+    }
+    
+    /// Request cancellation; returns whether a compaction was running.
+    pub fn cancel(&self) -> bool {
+        let status = self.status.lock().unwrap();
+        if status.running {
+            self.cancel.store(true, Ordering::Relaxed);
+        }
+        status.running
+    }
+    
+    pub fn cancel_flag(&self) -> &AtomicBool {
+        &self.cancel
+    }
+    
+    pub fn status(&self) -> CompactionStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn at(hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(10 * 86400 + hour * 3600 + minute * 60)
+    }
+    
+    fn window(start: u8, end: u8) -> MaintenancePolicy {
+        MaintenancePolicy {
+            window_start_hour: Some(start),
+            window_end_hour: Some(end),
+            ..Default::default()
+        }
+    }
+    
+    #[test]
+    fn window_remaining_counts_down_to_the_end_hour() {
+        assert_eq!(window(2, 5).window_remaining(at(3, 30)), Some(Duration::from_secs(90 * 60)));
+        assert_eq!(window(22, 4).window_remaining(at(23, 0)), Some(Duration::from_secs(5 * 3600)));
+        assert_eq!(window(22, 4).window_remaining(at(1, 45)), Some(Duration::from_secs(135 * 60)));
+    }
+    
+    #[test]
+    fn window_remaining_is_zero_outside_the_window() {
+        assert_eq!(window(2, 5).window_remaining(at(5, 0)), Some(Duration::ZERO));
+        assert_eq!(window(22, 4).window_remaining(at(12, 0)), Some(Duration::ZERO));
+        assert_eq!(MaintenancePolicy::default().window_remaining(at(12, 0)), None);
+    }
+    
+    #[test]
+    fn validate_rejects_an_empty_window() {
+        assert!(window(3, 3).validate().is_err());
+        assert!(window(3, 4).validate().is_ok());
+        assert!(window(23, 0).validate().is_ok());
+    }
+}
@@ -0,0 +1,506 @@
+//! Read-only access to restic repositories, for importing snapshots.
+//!
+//! Supports repository format versions 1 and 2 (zstd compression) on a local
+//! filesystem. The password unlocks one of the key files, which holds the
+//! master key for everything else. Only what's needed to walk a snapshot's
+//! trees and stream file contents is implemented.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use aes::cipher::{BlockEncrypt, KeyInit, KeyIvInit, StreamCipher};
+use aes::{Aes128, Aes256};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use poly1305::Poly1305;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::block::read_exact_at;
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+#[derive(Error, Debug)]
+pub enum ResticError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    
+    #[error("Malformed repository data: {0}")]
+    Json(#[from] serde_json::Error),
+    
+    #[error("No key file could be opened with the given password")]
+    WrongPassword,
+    
+    #[error("Unsupported repository: {0}")]
+    Unsupported(String),
+    
+    #[error("Corrupt repository data: {0}")]
+    Corrupt(String),
+    
+    #[error("Not found in repository: {0}")]
+    NotFound(String),
+}
+
+type Result<T> = std::result::Result<T, ResticError>;
+
+#[derive(Deserialize)]
+struct KeyFile {
+    kdf: String,
+    #[serde(rename = "N")]
+    n: u64,
+    r: u32,
+    p: u32,
+    salt: String,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct MasterKeyJson {
+    mac: MacKeyJson,
+    encrypt: String,
+}
+
+#[derive(Deserialize)]
+struct MacKeyJson {
+    k: String,
+    r: String,
+}
+
+/// AES-256-CTR encryption key plus the Poly1305-AES MAC key.
+struct Key {
+    encrypt: [u8; 32],
+    mac_k: [u8; 16],
+    mac_r: [u8; 16],
+}
+
+impl Key {
+    fn from_bytes(bytes: &[u8]) -> Option<Key> {
+        if bytes.len() != 64 {
+            return None;
+        }
+        Some(Key {
+            encrypt: bytes[..32].try_into().ok()?,
+            mac_k: bytes[32..48].try_into().ok()?,
+            mac_r: bytes[48..].try_into().ok()?,
+        })
+    }
+    
+    /// Check the MAC and decrypt `IV || ciphertext || MAC`; `None` if the MAC doesn't match.
+    fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < 32 {
+            return None;
+        }
+        let (nonce, rest) = data.split_at(16);
+        let (ciphertext, mac) = rest.split_at(rest.len() - 16);
+        
+        // Poly1305-AES: r from the key, s = AES-128(k, nonce)
+        let mut s = aes::Block::clone_from_slice(nonce);
+        Aes128::new(&self.mac_k.into()).encrypt_block(&mut s);
+        let mut poly_key = [0u8; 32];
+        poly_key[..16].copy_from_slice(&self.mac_r);
+        poly_key[16..].copy_from_slice(&s);
+        let tag = Poly1305::new(&poly_key.into()).compute_unpadded(ciphertext);
+        if tag.as_slice() != mac {
+            return None;
+        }
+        
+        let mut plaintext = ciphertext.to_vec();
+        Aes256Ctr::new(&self.encrypt.into(), nonce.into()).apply_keystream(&mut plaintext);
+        Some(plaintext)
+    }
+}
+
+#[derive(Deserialize)]
+struct Config {
+    version: u32,
+}
+
+/// Index files are either `{"packs": [...]}` or, in the legacy format, a bare list of packs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IndexFile {
+    Current { packs: Vec<IndexPack> },
+    Legacy(Vec<IndexPack>),
+}
+
+#[derive(Deserialize)]
+struct IndexPack {
+    id: String,
+    blobs: Vec<IndexBlob>,
+}
+
+#[derive(Deserialize)]
+struct IndexBlob {
+    id: String,
+    offset: u64,
+    length: u64,
+    #[serde(default)]
+    uncompressed_length: Option<u64>,
+}
+
+struct BlobLocation {
+    pack: String,
+    offset: u64,
+    length: u64,
+    uncompressed_length: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Snapshot {
+    pub time: String,
+    pub tree: String,
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Tree {
+    #[serde(default)]
+    nodes: Vec<Node>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    content: Vec<String>,
+    #[serde(default)]
+    subtree: Option<String>,
+}
+
+/// A regular file in a snapshot, with its content as an ordered list of data blobs.
+pub struct SnapshotFile {
+    /// Slash-separated path relative to the snapshot root.
+    pub path: String,
+    pub name: String,
+    pub size: u64,
+    pub content: Vec<String>,
+}
+
+pub struct Repository {
+    root: PathBuf,
+    key: Key,
+    blobs: HashMap<String, BlobLocation>,
+}
+
+impl Repository {
+    /// Unlock the repository with `password` and load its blob index.
+    pub fn open(root: &Path, password: &str) -> Result<Repository> {
+        let key = Self::unlock(root, password)?;
+        let mut repo = Repository {
+            root: root.to_path_buf(),
+            key,
+            blobs: HashMap::new(),
+        };
+        
+        let config: Config = serde_json::from_slice(&repo.load_unpacked(&root.join("config"))?)?;
+        if config.version != 1 && config.version != 2 {
+            return Err(ResticError::Unsupported(format!("repository version {}", config.version)));
+        }
+        
+        for path in list_dir(&root.join("index"))? {
+            let index = match serde_json::from_slice(&repo.load_unpacked(&path)?)? {
+                IndexFile::Current { packs } | IndexFile::Legacy(packs) => packs,
+            };
+            for pack in index {
+                for blob in pack.blobs {
+                    repo.blobs.insert(blob.id, BlobLocation {
+                        pack: pack.id.clone(),
+                        offset: blob.offset,
+                        length: blob.length,
+                        uncompressed_length: blob.uncompressed_length,
+                    });
+                }
+            }
+        }
+        
+        Ok(repo)
+    }
+    
+    /// Try each key file in turn, as restic does. Key files using a key
+    /// derivation function other than scrypt are skipped; they are only an
+    /// error if there is nothing else to try.
+    fn unlock(root: &Path, password: &str) -> Result<Key> {
+        let mut unsupported_kdf = None;
+        let mut tried_any = false;
+        for path in list_dir(&root.join("keys"))? {
+            let key_file: KeyFile = serde_json::from_slice(&fs::read(&path)?)?;
+            if key_file.kdf != "scrypt" {
+                unsupported_kdf = Some(key_file.kdf);
+                continue;
+            }
+            tried_any = true;
+            if !key_file.n.is_power_of_two() {
+                return Err(ResticError::Corrupt(format!("scrypt N = {} in {}", key_file.n, path.display())));
+            }
+            let params = scrypt::Params::new(key_file.n.trailing_zeros() as u8, key_file.r, key_file.p, 64)
+                .map_err(|e| ResticError::Corrupt(format!("scrypt parameters in {}: {}", path.display(), e)))?;
+            
+            let mut derived = [0u8; 64];
+            scrypt::scrypt(password.as_bytes(), &decode_base64(&key_file.salt)?, &params, &mut derived)
+                .map_err(|e| ResticError::Corrupt(e.to_string()))?;
+            let user_key = Key::from_bytes(&derived).expect("64 bytes derived");
+            
+            // A MAC mismatch just means this key file belongs to another password
+            let Some(plaintext) = user_key.decrypt(&decode_base64(&key_file.data)?) else {
+                continue;
+            };
+            let master: MasterKeyJson = serde_json::from_slice(&plaintext)?;
+            let mut bytes = decode_base64(&master.encrypt)?;
+            bytes.extend(decode_base64(&master.mac.k)?);
+            bytes.extend(decode_base64(&master.mac.r)?);
+            return Key::from_bytes(&bytes)
+                .ok_or_else(|| ResticError::Corrupt(format!("master key in {}", path.display())));
+        }
+        
+        match unsupported_kdf {
+            Some(kdf) if !tried_any => Err(ResticError::Unsupported(format!("key derivation function {}", kdf))),
+            _ => Err(ResticError::WrongPassword),
+        }
+    }
+    
+    /// Decrypt a standalone repository file (config, index, snapshot), undoing
+    /// the version 2 compression header if present.
+    fn load_unpacked(&self, path: &Path) -> Result<Vec<u8>> {
+        let plaintext = self.key.decrypt(&fs::read(path)?)
+            .ok_or_else(|| ResticError::Corrupt(format!("MAC mismatch in {}", path.display())))?;
+        match plaintext.first() {
+            Some(b'{') | Some(b'[') => Ok(plaintext),
+            Some(2) => Ok(zstd::stream::decode_all(&plaintext[1..])?),
+            _ => Err(ResticError::Corrupt(format!("unknown encoding in {}", path.display()))),
+        }
+    }
+    
+    pub fn snapshots(&self) -> Result<Vec<(String, Snapshot)>> {
+        let mut snapshots = Vec::new();
+        for path in list_dir(&self.root.join("snapshots"))? {
+            let id = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            snapshots.push((id, serde_json::from_slice(&self.load_unpacked(&path)?)?));
+        }
+        Ok(snapshots)
+    }
+    
+    /// Resolve `latest` (or `None`) to the newest snapshot, anything else to
+    /// the snapshot whose id starts with it.
+    pub fn find_snapshot(&self, spec: Option<&str>) -> Result<(String, Snapshot)> {
+        let snapshots = self.snapshots()?;
+        match spec {
+            None | Some("latest") => snapshots.into_iter()
+                .max_by_key(|(_, snapshot)| parse_time(&snapshot.time))
+                .ok_or_else(|| ResticError::NotFound("no snapshots".to_string())),
+            Some(prefix) => {
+                let mut matches = snapshots.into_iter().filter(|(id, _)| id.starts_with(prefix));
+                match (matches.next(), matches.next()) {
+                    (Some(found), None) => Ok(found),
+                    (None, _) => Err(ResticError::NotFound(format!("snapshot {}", prefix))),
+                    (Some(_), Some(_)) => Err(ResticError::NotFound(format!("snapshot {} is ambiguous", prefix))),
+                }
+            }
+        }
+    }
+    
+    /// Read, decrypt and decompress a blob, checking it against its id.
+    pub fn read_blob(&self, id: &str) -> Result<Vec<u8>> {
+        let location = self.blobs.get(id)
+            .ok_or_else(|| ResticError::NotFound(format!("blob {}", id)))?;
+        if location.pack.len() < 2 {
+            return Err(ResticError::Corrupt(format!("pack id {}", location.pack)));
+        }
+        
+        let pack_path = self.root.join("data").join(&location.pack[..2]).join(&location.pack);
+        let mut encrypted = vec![0u8; location.length as usize];
+        read_exact_at(&File::open(&pack_path)?, &mut encrypted, location.offset)?;
+        
+        let plaintext = self.key.decrypt(&encrypted)
+            .ok_or_else(|| ResticError::Corrupt(format!("MAC mismatch for blob {}", id)))?;
+        let plaintext = match location.uncompressed_length {
+            Some(len) => zstd::bulk::decompress(&plaintext, len as usize)?,
+            None => plaintext,
+        };
+        
+        if hex::encode(Sha256::digest(&plaintext)) != id {
+            return Err(ResticError::Corrupt(format!("content of blob {} does not match its id", id)));
+        }
+        Ok(plaintext)
+    }
+    
+    /// All regular files reachable from `tree`, and the number of other
+    /// entries (symlinks, devices, ...) that were skipped.
+    pub fn files(&self, tree: &str) -> Result<(Vec<SnapshotFile>, usize)> {
+        let mut files = Vec::new();
+        let mut skipped = 0;
+        let mut pending = vec![(tree.to_string(), String::new())];
+        
+        while let Some((tree_id, dir)) = pending.pop() {
+            let tree: Tree = serde_json::from_slice(&self.read_blob(&tree_id)?)?;
+            for node in tree.nodes {
+                let path = if dir.is_empty() { node.name.clone() } else { format!("{}/{}", dir, node.name) };
+                match (node.kind.as_str(), node.subtree) {
+                    ("file", _) => files.push(SnapshotFile {
+                        path,
+                        name: node.name,
+                        size: node.size,
+                        content: node.content,
+                    }),
+                    ("dir", Some(subtree)) => pending.push((subtree, path)),
+                    _ => skipped += 1,
+                }
+            }
+        }
+        
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok((files, skipped))
+    }
+    
+    pub fn file_reader<'a>(&'a self, file: &'a SnapshotFile) -> BlobReader<'a> {
+        BlobReader {
+            repo: self,
+            content: file.content.iter(),
+            current: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+/// Streams a file's data blobs in order, loading one blob at a time.
+pub struct BlobReader<'a> {
+    repo: &'a Repository,
+    content: std::slice::Iter<'a, String>,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for BlobReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.content.next() {
+                Some(id) => {
+                    self.current = self.repo.read_blob(id)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>> {
+    BASE64.decode(data).map_err(|e| ResticError::Corrupt(format!("base64: {}", e)))
+}
+
+/// Files in `dir` and (for `data/`-style layouts) one level of subdirectories.
+fn list_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            for sub in fs::read_dir(entry.path())? {
+                paths.push(sub?.path());
+            }
+        } else {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Seconds since the epoch for an RFC 3339 timestamp like
+/// `2024-05-01T10:20:30.123456789+02:00`; fractional seconds are ignored.
+fn parse_time(time: &str) -> Option<i64> {
+    let num = |range: std::ops::Range<usize>| time.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    
+    let zone = time[19..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone.as_bytes().first()? {
+        b'Z' | b'z' => 0,
+        sign @ (b'+' | b'-') => {
+            let minutes = zone.get(1..3)?.parse::<i64>().ok()? * 60 + zone.get(4..6)?.parse::<i64>().ok()?;
+            if *sign == b'+' { minutes * 60 } else { -minutes * 60 }
+        }
+        _ => return None,
+    };
+    
+    // Days from the civil date, per Howard Hinnant's algorithm
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn fixture(version: u32) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("tests/fixtures/restic/v{}/repo", version))
+    }
+    
+    #[test]
+    fn parse_time_applies_the_utc_offset() {
+        let utc = Some(1714546800);
+        assert_eq!(parse_time("2024-05-01T07:00:00Z"), utc);
+        assert_eq!(parse_time("2024-05-01T07:00:00z"), utc);
+        assert_eq!(parse_time("2024-05-01T12:00:00+05:00"), utc);
+        assert_eq!(parse_time("2024-05-01T12:00:00.123456789+05:00"), utc);
+        assert_eq!(parse_time("2024-05-01T03:30:00-03:30"), utc);
+        assert_eq!(parse_time("2024-04-30T23:00:00.5-08:00"), utc);
+        assert_eq!(parse_time("1970-01-01T00:00:00Z"), Some(0));
+    }
+    
+    #[test]
+    fn parse_time_rejects_timestamps_without_a_zone() {
+        assert_eq!(parse_time("2024-05-01T07:00:00"), None);
+        assert_eq!(parse_time("2024-05-01T07:00:00.25"), None);
+        assert_eq!(parse_time("2024-05-01"), None);
+        assert_eq!(parse_time("yesterday"), None);
+    }
+    
+    #[test]
+    fn unlock_skips_key_files_with_other_kdfs() {
+        for version in [1, 2] {
+            let keys = list_dir(&fixture(version).join("keys")).unwrap();
+            let first: KeyFile = serde_json::from_slice(&fs::read(&keys[0]).unwrap()).unwrap();
+            assert_ne!(first.kdf, "scrypt");
+            
+            assert!(Repository::open(&fixture(version), "secret").is_ok());
+            assert!(matches!(Repository::unlock(&fixture(version), "wrong"), Err(ResticError::WrongPassword)));
+        }
+    }
+    
+    #[test]
+    fn unlock_reports_an_unsupported_kdf_when_no_key_file_can_be_tried() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("keys")).unwrap();
+        let keys = list_dir(&fixture(1).join("keys")).unwrap();
+        fs::copy(&keys[0], dir.path().join("keys").join(keys[0].file_name().unwrap())).unwrap();
+        
+        assert!(matches!(Repository::unlock(dir.path(), "secret"), Err(ResticError::Unsupported(_))));
+    }
+    
+    #[test]
+    fn newest_snapshot_accounts_for_utc_offsets() {
+        for version in [1, 2] {
+            let repo = Repository::open(&fixture(version), "secret").unwrap();
+            let (_, snapshot) = repo.find_snapshot(None).unwrap();
+            assert_eq!(snapshot.paths, ["/data"]);
+        }
+    }
+}
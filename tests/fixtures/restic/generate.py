@@ -0,0 +1,193 @@
+"""
+Generate the small restic repositories used by the import tests.
+
+restic itself isn't needed: the repositories are written directly in its
+format, with a low scrypt cost so the tests stay fast. Each one has a key file
+with an unsupported KDF (sorted first), a key file for another password, and
+the key for "secret". There are two snapshots: an older one at
+12:00+05:00 and the newest at 08:00Z, so picking the newest snapshot needs the
+UTC offset. `expected.json` lists the newest snapshot's ID, its regular files
+by path with their SHA-256, and the number of entries skipped (symlinks).
+
+`generate_with_restic.py` writes a fixture in the same layout using a real
+`restic` binary, to check these against what restic actually produces.
+
+Requires the `cryptography` package and the `zstd` command line tool.
+
+    python3 tests/fixtures/restic/generate.py
+"""
+
+import base64
+import hashlib
+import json
+import os
+import random
+import shutil
+import subprocess
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.poly1305 import Poly1305
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+PASSWORD = "secret"
+
+
+def sha256(data):
+    return hashlib.sha256(data).hexdigest()
+
+
+def zstd(data):
+    return subprocess.run(["zstd", "-q", "-c"], input=data, capture_output=True, check=True).stdout
+
+
+def b64(data):
+    return base64.b64encode(data).decode()
+
+
+class Repo:
+    def __init__(self, root, version, rng):
+        self.root = root
+        self.version = version
+        self.rng = rng
+        self.master = (rng.randbytes(32), rng.randbytes(16), rng.randbytes(16))
+        self.pending = []
+        self.packs = []
+        self.blobs = set()
+        for name in ("keys", "index", "snapshots", "data", "locks"):
+            os.makedirs(os.path.join(root, name), exist_ok=True)
+
+    def encrypt(self, key, plaintext):
+        encrypt_key, mac_k, mac_r = key
+        nonce = self.rng.randbytes(16)
+        encryptor = Cipher(algorithms.AES(encrypt_key), modes.CTR(nonce)).encryptor()
+        ciphertext = encryptor.update(plaintext) + encryptor.finalize()
+        aes = Cipher(algorithms.AES(mac_k), modes.ECB()).encryptor()
+        s = aes.update(nonce) + aes.finalize()
+        return nonce + ciphertext + Poly1305.generate_tag(mac_r + s, ciphertext)
+
+    def write(self, relative, data):
+        path = os.path.join(self.root, relative)
+        os.makedirs(os.path.dirname(path), exist_ok=True)
+        with open(path, "wb") as f:
+            f.write(data)
+
+    def add_key(self, password, kdf="scrypt"):
+        salt = self.rng.randbytes(64)
+        derived = hashlib.scrypt(password.encode(), salt=salt, n=1024, r=8, p=1, dklen=64)
+        user_key = (derived[:32], derived[32:48], derived[48:])
+        master = json.dumps({
+            "mac": {"k": b64(self.master[1]), "r": b64(self.master[2])},
+            "encrypt": b64(self.master[0]),
+        }).encode()
+        key_file = {
+            "created": "2024-01-01T00:00:00Z", "username": "user", "hostname": "host",
+            "kdf": kdf, "N": 1024, "r": 8, "p": 1,
+            "salt": b64(salt), "data": b64(self.encrypt(user_key, master)),
+        }
+        # Key files are named by their SHA-256; the unsupported one must sort first
+        while True:
+            raw = json.dumps(key_file).encode()
+            if kdf == "scrypt" or sha256(raw).startswith("00"):
+                break
+            key_file["hostname"] = "host-%d" % self.rng.randrange(1 << 30)
+        self.write(os.path.join("keys", sha256(raw)), raw)
+
+    def add_unpacked(self, kind, obj):
+        plaintext = json.dumps(obj).encode()
+        if self.version == 2 and kind != "config":
+            plaintext = b"\x02" + zstd(plaintext)
+        ciphertext = self.encrypt(self.master, plaintext)
+        name = "config" if kind == "config" else os.path.join(kind, sha256(ciphertext))
+        self.write(name, ciphertext)
+        return os.path.basename(name)
+
+    def add_blob(self, plaintext, kind):
+        blob_id = sha256(plaintext)
+        if blob_id in self.blobs:
+            return blob_id
+        self.blobs.add(blob_id)
+        entry = {"id": blob_id, "type": kind}
+        if self.version == 2 and kind == "data" and self.rng.random() < 0.5:
+            entry["uncompressed_length"] = len(plaintext)
+            plaintext = zstd(plaintext)
+        self.pending.append((entry, self.encrypt(self.master, plaintext)))
+        if len(self.pending) >= 3:
+            self.flush()
+        return blob_id
+
+    def flush(self):
+        if not self.pending:
+            return
+        body, entries = b"", []
+        for entry, ciphertext in self.pending:
+            entries.append(dict(entry, offset=len(body), length=len(ciphertext)))
+            body += ciphertext
+        # Stands in for the encrypted pack header, which the importer doesn't read
+        body += self.rng.randbytes(100)
+        pack_id = sha256(body)
+        self.write(os.path.join("data", pack_id[:2], pack_id), body)
+        self.packs.append({"id": pack_id, "blobs": entries})
+        self.pending.clear()
+
+
+def generate(version):
+    root = os.path.join(HERE, "v%d" % version)
+    shutil.rmtree(root, ignore_errors=True)
+    rng = random.Random(version)
+    repo = Repo(os.path.join(root, "repo"), version, rng)
+
+    repo.add_key(PASSWORD, kdf="argon2id")
+    repo.add_key("another password")
+    repo.add_key(PASSWORD)
+    repo.add_unpacked("config", {"version": version, "id": sha256(b"%d" % version), "chunker_polynomial": "3da3358b4dc173"})
+
+    pool = [rng.randbytes(rng.randint(1, 20000)) for _ in range(6)]
+    pool.append(b"\x00" * 30000)
+    files = {}
+    skipped = 0
+
+    def tree(path, depth):
+        nonlocal skipped
+        nodes = []
+        for i in range(3):
+            name = "f%d.bin" % i
+            chunks = [rng.choice(pool) for _ in range(rng.randint(1, 3))]
+            data = b"".join(chunks)
+            files[path + name] = sha256(data)
+            nodes.append({
+                "name": name, "type": "file", "size": len(data),
+                "content": [repo.add_blob(chunk, "data") for chunk in chunks],
+            })
+        if depth == 0:
+            files[path + "empty"] = sha256(b"")
+            nodes.append({"name": "empty", "type": "file", "size": 0})
+        nodes.append({"name": "link", "type": "symlink", "linktarget": "f0.bin"})
+        skipped += 1
+        if depth < 2:
+            nodes.append({"name": "d%d" % depth, "type": "dir", "subtree": tree("%sd%d/" % (path, depth), depth + 1)})
+        return repo.add_blob((json.dumps({"nodes": nodes}) + "\n").encode(), "tree")
+
+    old_tree = tree("", 1)
+    files.clear()
+    skipped = 0
+    new_tree = tree("", 0)
+    repo.flush()
+
+    # Version 1 repositories may still hold index files in the legacy list format
+    if version == 1:
+        repo.add_unpacked("index", repo.packs[:2])
+    else:
+        repo.add_unpacked("index", {"packs": repo.packs[:2]})
+    repo.add_unpacked("index", {"packs": repo.packs[2:]})
+
+    repo.add_unpacked("snapshots", {"time": "2024-05-01T12:00:00.5+05:00", "tree": old_tree, "paths": ["/old"], "hostname": "host"})
+    snapshot = repo.add_unpacked("snapshots", {"time": "2024-05-01T08:00:00Z", "tree": new_tree, "paths": ["/data"], "hostname": "host"})
+
+    with open(os.path.join(root, "expected.json"), "w") as f:
+        json.dump({"snapshot": snapshot, "paths": ["/data"], "skipped": skipped, "files": files}, f, indent=2, sort_keys=True)
+        f.write("\n")
+
+
+if __name__ == "__main__":
+    for version in (1, 2):
+        generate(version)
@@ -0,0 +1,83 @@
+"""
+Generate an import fixture with a real `restic` binary, as a cross-check on
+the hand-written repositories from `generate.py`.
+
+The fixture lands in `restic-<version>/` next to this script, with the same
+layout and `expected.json` as the others, and the import tests pick it up
+automatically. The snapshot covers a few small files, a file large enough to
+be split into several chunks, a duplicate, an empty file and a symlink; the
+restic tree keeps the absolute path of the backed up directory, so file paths
+in `expected.json` carry that prefix.
+
+Requires `restic` on the PATH.
+
+    python3 tests/fixtures/restic/generate_with_restic.py
+"""
+
+import hashlib
+import json
+import os
+import random
+import shutil
+import subprocess
+import tempfile
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+PASSWORD = "secret"
+
+
+def restic(*args, **kwargs):
+    return subprocess.run(["restic", *args], capture_output=True, check=True, text=True, **kwargs).stdout
+
+
+def write_tree(data_dir, rng):
+    """Write the files to back up; returns how many entries the import skips."""
+    os.makedirs(os.path.join(data_dir, "d0", "d1"))
+    contents = {
+        "f0.bin": rng.randbytes(1000),
+        "d0/f0.bin": rng.randbytes(70000),
+        "d0/d1/f0.bin": rng.randbytes(5 * 1024 * 1024),
+        "empty": b"",
+    }
+    contents["d0/d1/f1.bin"] = contents["d0/f0.bin"]
+    for path, data in contents.items():
+        with open(os.path.join(data_dir, path), "wb") as f:
+            f.write(data)
+    os.symlink("f0.bin", os.path.join(data_dir, "link"))
+    return 1
+
+
+def main():
+    version = restic("version").split()[1]
+    root = os.path.join(HERE, "restic-" + version)
+    shutil.rmtree(root, ignore_errors=True)
+    os.makedirs(root)
+
+    env = dict(os.environ, RESTIC_REPOSITORY=os.path.join(root, "repo"), RESTIC_PASSWORD=PASSWORD)
+    rng = random.Random(698)
+    with tempfile.TemporaryDirectory() as work:
+        data_dir = os.path.join(work, "data")
+        os.makedirs(data_dir)
+        skipped = write_tree(data_dir, rng)
+
+        restic("init", env=env)
+        restic("backup", "--host", "host", data_dir, env=env)
+        snapshot = json.loads(restic("snapshots", "--json", "--latest", "1", env=env))[0]
+
+        prefix = data_dir.lstrip("/")
+        files = {}
+        for dirpath, _, filenames in os.walk(data_dir):
+            for name in filenames:
+                path = os.path.join(dirpath, name)
+                if os.path.islink(path):
+                    continue
+                with open(path, "rb") as f:
+                    digest = hashlib.sha256(f.read()).hexdigest()
+                files[os.path.join(prefix, os.path.relpath(path, data_dir))] = digest
+
+    with open(os.path.join(root, "expected.json"), "w") as f:
+        json.dump({"snapshot": snapshot["id"], "paths": snapshot["paths"], "skipped": skipped, "files": files}, f, indent=2, sort_keys=True)
+
+
+if __name__ == "__main__":
+    main()
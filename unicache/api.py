@@ -37,6 +37,8 @@ import hashlib
 import time
 
 from unicache import Cache as LowLevelCache, download_file_fast, get_download_info, DownloadError
+# Re-raised unchanged, so `unicache.CorruptionError` catches it from either API
+from unicache.unicache_rs import CorruptionError
 from unicache.cache_utils import download_and_store
 
 
@@ -55,6 +57,17 @@ class DownloadFailedError(UniCacheError):
     pass
 
 
+class OperationTimeoutError(UniCacheError, TimeoutError):
+    """
+    Raised when a cache operation exceeds its timeout; partial state is rolled back.
+    
+    The timeout is checked between reads and writes and while waiting for
+    locks. A single read or write that hangs (e.g. on an unresponsive network
+    filesystem) can't be interrupted, so the error is raised once it returns.
+    """
+    pass
+
+
 class UniCache:
     """
     High-level interface for UniCache - effortless file caching.
@@ -66,13 +79,28 @@ class UniCache:
         cache_dir: Directory to store cache files (default: ~/.unicache)
         block_size: Block size for deduplication (default: 1MB)
         auto_cleanup: Whether to automatically clean up temporary files (default: True)
+        file_hash_algorithm: Whole-file digest algorithm recorded for each file:
+            "blake3", "sha256" or "md5" (default: "blake3")
+        page_cache_hints: Advise the kernel to drop pages behind ingest and
+            retrieval so large files don't evict the page cache (default: False)
+        small_file_threshold: Files smaller than this many bytes are packed
+            together into shared blocks to cut per-file overhead; 0 disables
+            packing (default: 0)
+        timeout: Default timeout in seconds for adding, retrieving and
+            removing files, including waiting for locks (default: None, no
+            limit). A single hung read or write can't be interrupted, so an
+            operation may overrun it by as long as that call takes
     """
     
     def __init__(
         self,
         cache_dir: Optional[Union[str, Path]] = None,
         block_size: int = 1024 * 1024,  # 1MB
-        auto_cleanup: bool = True
+        auto_cleanup: bool = True,
+        file_hash_algorithm: str = "blake3",
+        page_cache_hints: bool = False,
+        small_file_threshold: int = 0,
+        timeout: Optional[float] = None
     ):
         if cache_dir is None:
             cache_dir = Path.home() / ".unicache"
@@ -84,7 +112,14 @@ class UniCache:
         self.auto_cleanup = auto_cleanup
         
         # Initialize the low-level cache
-        self._cache = LowLevelCache(block_size=block_size, cache_dir=str(self.cache_dir))
+        self._cache = LowLevelCache(
+            block_size=block_size,
+            cache_dir=str(self.cache_dir),
+            file_hash_algorithm=file_hash_algorithm,
+            page_cache_hints=page_cache_hints,
+            small_file_threshold=small_file_threshold,
+            timeout=timeout
+        )
         
         # Track temporary files for cleanup
         self._temp_files = set()
@@ -147,7 +182,8 @@ class UniCache:
         self,
         file_path: Union[str, Path],
         file_id: Optional[str] = None,
-        copy_file: bool = False
+        copy_file: bool = False,
+        timeout: Optional[float] = None
     ) -> str:
         """
         Add a local file to the cache.
@@ -156,12 +192,14 @@ class UniCache:
             file_path: Path to the local file
             file_id: Custom ID for the file (auto-generated if None)
             copy_file: Whether to copy the file before adding (preserves original)
+            timeout: Timeout in seconds, overriding the cache default
             
         Returns:
             File ID that can be used to retrieve the file
             
         Raises:
             FileNotFoundError: If the local file doesn't exist
+            OperationTimeoutError: If storing the file times out
             UniCacheError: If storing the file fails
         """
         file_path = Path(file_path)
@@ -191,7 +229,7 @@ class UniCache:
                 file_to_store = file_path
             
             # Store the file in cache
-            actual_file_id = self._cache.store_file(str(file_to_store), file_id)
+            actual_file_id = self._cache.store_file(str(file_to_store), file_id, timeout=timeout)
             
             # Clean up temporary file if created
             if copy_file and self.auto_cleanup and temp_path.exists():
@@ -203,10 +241,17 @@ class UniCache:
             
             return actual_file_id
             
+        except TimeoutError as e:
+            raise OperationTimeoutError(f"Timed out adding file {file_path}: {e}")
         except Exception as e:
             raise UniCacheError(f"Failed to add file {file_path}: {e}")
     
-    def get(self, file_id: str, output_dir: Optional[Union[str, Path]] = None) -> Path:
+    def get(
+        self,
+        file_id: str,
+        output_dir: Optional[Union[str, Path]] = None,
+        timeout: Optional[float] = None
+    ) -> Path:
         """
         Get a file from the cache and return its path.
         
@@ -215,12 +260,15 @@ class UniCache:
         Args:
             file_id: ID of the file to retrieve
             output_dir: Directory to extract the file to (uses temp dir if None)
+            timeout: Timeout in seconds, overriding the cache default
             
         Returns:
             Path to the retrieved file
             
         Raises:
             FileNotFoundError: If the file is not found in cache
+            OperationTimeoutError: If retrieval times out (no partial file is left)
+            CorruptionError: If the file is quarantined
         """
         if not self._file_exists(file_id):
             raise FileNotFoundError(f"File not found in cache: {file_id}")
@@ -238,7 +286,7 @@ class UniCache:
             output_path = output_dir / f"{file_id}_{int(time.time())}"
             
             # Retrieve the file
-            self._cache.retrieve_file(file_id, str(output_path))
+            self._cache.retrieve_file(file_id, str(output_path), timeout=timeout)
             
             # Track for cleanup if needed
             if self.auto_cleanup:
@@ -246,22 +294,34 @@ class UniCache:
             
             return output_path
             
+        except TimeoutError as e:
+            raise OperationTimeoutError(f"Timed out retrieving file {file_id}: {e}")
+        except CorruptionError:
+            raise
         except Exception as e:
             raise UniCacheError(f"Failed to retrieve file {file_id}: {e}")
     
-    def copy_to(self, file_id: str, output_path: Union[str, Path]) -> Path:
+    def copy_to(
+        self,
+        file_id: str,
+        output_path: Union[str, Path],
+        timeout: Optional[float] = None
+    ) -> Path:
         """
         Copy a file from the cache to a specific location.
         
         Args:
             file_id: ID of the file to retrieve
             output_path: Where to copy the file
+            timeout: Timeout in seconds, overriding the cache default
             
         Returns:
             Path to the copied file
             
         Raises:
             FileNotFoundError: If the file is not found in cache
+            OperationTimeoutError: If retrieval times out (no partial file is left)
+            CorruptionError: If the file is quarantined
         """
         if not self._file_exists(file_id):
             raise FileNotFoundError(f"File not found in cache: {file_id}")
@@ -271,26 +331,90 @@ class UniCache:
             output_path.parent.mkdir(parents=True, exist_ok=True)
             
             # Retrieve the file directly to the specified location
-            self._cache.retrieve_file(file_id, str(output_path))
+            self._cache.retrieve_file(file_id, str(output_path), timeout=timeout)
             
             return output_path
             
+        except TimeoutError as e:
+            raise OperationTimeoutError(f"Timed out copying file {file_id}: {e}")
+        except CorruptionError:
+            raise
         except Exception as e:
             raise UniCacheError(f"Failed to copy file {file_id} to {output_path}: {e}")
     
-    def remove(self, file_id: str) -> bool:
+    def copy_regions(
+        self,
+        file_id: str,
+        output_path: Union[str, Path],
+        block_indices: Optional[List[int]] = None,
+        byte_ranges: Optional[List[Tuple[int, int]]] = None
+    ) -> int:
+        """
+        Write only selected blocks or byte ranges of a cached file.
+        
+        The output file is created if needed and sized to the full file
+        length; regions that aren't written stay sparse and existing content
+        outside the requested regions is left alone. This allows resumable,
+        out-of-order or parallel reconstruction across several calls.
+        
+        Args:
+            file_id: ID of the file to retrieve
+            output_path: Output file to fill in
+            block_indices: Indices of blocks to write (see block_layout())
+            byte_ranges: (start, end) byte ranges to write, end exclusive
+            
+        Returns:
+            Number of bytes written
+            
+        Raises:
+            CorruptionError: If the file is quarantined
+            UniCacheError: If the file is missing or a region is out of range
+        """
+        try:
+            output_path = Path(output_path)
+            output_path.parent.mkdir(parents=True, exist_ok=True)
+            return self._cache.retrieve_regions(
+                file_id,
+                str(output_path),
+                block_indices=block_indices,
+                byte_ranges=byte_ranges
+            )
+        except CorruptionError:
+            raise
+        except Exception as e:
+            raise UniCacheError(f"Failed to copy regions of {file_id} to {output_path}: {e}")
+    
+    def block_layout(self, file_id: str) -> List[Tuple[int, int, str]]:
+        """
+        Get the (offset, size, block_hash) of each block of a cached file.
+        
+        Raises:
+            FileNotFoundError: If the file is not found in cache
+        """
+        try:
+            return self._cache.get_block_layout(file_id)
+        except Exception as e:
+            raise FileNotFoundError(f"File not found in cache: {file_id}: {e}")
+    
+    def remove(self, file_id: str, timeout: Optional[float] = None) -> bool:
         """
         Remove a file from the cache.
         
         Args:
             file_id: ID of the file to remove
+            timeout: Timeout in seconds, overriding the cache default
             
         Returns:
             True if the file was removed, False if it wasn't found
+            
+        Raises:
+            OperationTimeoutError: If removal times out (the file stays in the cache)
         """
         try:
-            self._cache.remove_file(file_id)
+            self._cache.remove_file(file_id, timeout=timeout)
             return True
+        except TimeoutError as e:
+            raise OperationTimeoutError(f"Timed out removing file {file_id}: {e}")
         except:
             return False
     
@@ -341,9 +465,204 @@ class UniCache:
             "space_saved_bytes": space_saved,
             "space_saved_mb": space_saved / (1024 * 1024),
             "cache_directory": str(self.cache_dir),
-            "block_size": self.block_size
+            "block_size": self.block_size,
+            "compression": self._cache.get_compression_stats()
         }
     
+    def file_info(self, file_id: str) -> Dict[str, Any]:
+        """
+        Get metadata and storage statistics for a cached file.
+        
+        Args:
+            file_id: ID of the file
+            
+        Returns:
+            Dictionary with name, size, block count, raw vs stored bytes,
+            and a per-compression-algorithm breakdown
+            
+        Raises:
+            FileNotFoundError: If the file is not found in cache
+        """
+        try:
+            return self._cache.get_file_info(file_id)
+        except Exception as e:
+            raise FileNotFoundError(f"File not found in cache: {file_id}: {e}")
+    
+    def verify(self, file_id: str) -> bool:
+        """
+        Check a cached file's blocks against their hashes and the whole file
+        against its recorded digest.
+        
+        A corrupt file is quarantined, along with every file sharing a corrupt
+        block, and can't be retrieved until it passes reverify_quarantined()
+        or is cleared with clear_quarantine().
+        
+        Args:
+            file_id: ID of the file to verify
+            
+        Returns:
+            True if the content matches, False if it is corrupt
+            
+        Raises:
+            UniCacheError: If the file is missing or can't be read
+        """
+        try:
+            return self._cache.verify_file(file_id)
+        except Exception as e:
+            raise UniCacheError(f"Failed to verify file {file_id}: {e}")
+    
+    def quarantined(self) -> Dict[str, Dict[str, Any]]:
+        """
+        List quarantined files.
+        
+        Returns:
+            Dictionary mapping file ID to when the corruption was detected
+            (Unix time) and the hashes of the corrupt blocks
+        """
+        return self._cache.list_quarantined()
+    
+    def reverify_quarantined(self, file_ids: Optional[List[str]] = None) -> Dict[str, bool]:
+        """
+        Verify quarantined files again and release the ones that pass.
+        
+        Adding a file that contains a corrupt block rewrites that block, so
+        re-adding one affected file can repair the others sharing it.
+        
+        Args:
+            file_ids: Files to re-verify (default: all quarantined files)
+            
+        Returns:
+            Dictionary mapping file ID to whether it passed
+        """
+        try:
+            return self._cache.reverify_quarantined(file_ids)
+        except Exception as e:
+            raise UniCacheError(f"Failed to re-verify quarantined files: {e}")
+    
+    def clear_quarantine(self, file_ids: Optional[List[str]] = None) -> List[str]:
+        """
+        Release quarantined files without verifying them.
+        
+        Args:
+            file_ids: Files to release (default: all quarantined files)
+            
+        Returns:
+            IDs of the files that were released
+        """
+        return self._cache.clear_quarantine(file_ids)
+    
+    def compact(self) -> Dict[str, Any]:
+        """
+        Reclaim space left by removed files by rewriting the blocks file.
+        
+        Other cache operations carry on while it copies, and only wait for the
+        final switch to the new file. It can be stopped with cancel_compaction().
+        
+        Returns:
+            Dictionary with bytes before/after and whether it was cancelled
+        """
+        return self._cache.compact()
+    
+    def compaction_status(self) -> Dict[str, Any]:
+        """
+        Get progress of a running compaction, the current dead bytes, and the
+        outcome of the last compaction.
+        """
+        return self._cache.compaction_status()
+    
+    def cancel_compaction(self) -> bool:
+        """
+        Cancel a running compaction, leaving the blocks file unchanged.
+        
+        Returns:
+            True if a compaction was running
+        """
+        return self._cache.cancel_compaction()
+    
+    def set_maintenance_policy(self, **policy) -> None:
+        """
+        Configure automatic compaction.
+        
+        Keyword Args:
+            enabled: Whether automatic compaction runs at all (default: True)
+            dead_bytes_threshold: Minimum unreferenced bytes before compacting
+            dead_ratio_threshold: Minimum unreferenced fraction of the blocks file
+            idle_seconds: Required time without cache operations
+            window_start_hour: Start of the allowed window (UTC hour, 0-23)
+            window_end_hour: End of the allowed window (UTC hour, exclusive,
+                different from the start); an automatic compaction still
+                running then is cancelled
+            check_interval_seconds: How often the policy is evaluated
+        """
+        self._cache.set_maintenance_policy(**policy)
+    
+    def plan_eviction(self, target_free_bytes: int) -> Dict[str, Any]:
+        """
+        Preview what evict() would remove, without deleting anything.
+        
+        Files are picked least recently used first (by last add or retrieval).
+        Blocks shared with files that stay are not counted as freed.
+        
+        Args:
+            target_free_bytes: Stored bytes to free
+            
+        Returns:
+            Dictionary with the file IDs to evict, the stored bytes that would
+            be freed, their combined logical size, and whether the target is met
+        """
+        return self._cache.plan_eviction(target_free_bytes)
+    
+    def evict(self, target_free_bytes: int, timeout: Optional[float] = None) -> Dict[str, Any]:
+        """
+        Remove least recently used files until target_free_bytes are freed.
+        
+        The freed space is reclaimed from disk by the next compact().
+        
+        Args:
+            target_free_bytes: Stored bytes to free
+            timeout: Timeout in seconds for removing each file (default: cache default)
+            
+        Returns:
+            The executed plan, in the same form as plan_eviction()
+        """
+        try:
+            return self._cache.evict(target_free_bytes, timeout=timeout)
+        except TimeoutError as e:
+            raise OperationTimeoutError(f"Timed out evicting files: {e}")
+    
+    def import_restic(
+        self,
+        repo_path: Union[str, Path],
+        password: str,
+        snapshot: Optional[str] = None,
+        id_prefix: str = ""
+    ) -> Dict[str, Any]:
+        """
+        Import a snapshot from a local restic repository.
+        
+        File contents are decrypted and stored directly, without restoring
+        the snapshot to disk first. Each regular file is added under
+        id_prefix + its path within the snapshot.
+        
+        Args:
+            repo_path: Path to the restic repository
+            password: Repository password
+            snapshot: Snapshot ID or unique ID prefix (default: latest)
+            id_prefix: Prefix for the file IDs of imported files
+            
+        Returns:
+            Dictionary with the snapshot ID, its original paths, the imported
+            file IDs, total bytes, and the number of skipped non-file entries
+            
+        Raises:
+            UniCacheError: If the repository can't be opened or read. Files
+                imported before the failure stay in the cache.
+        """
+        try:
+            return self._cache.import_restic(str(repo_path), password, snapshot, id_prefix)
+        except Exception as e:
+            raise UniCacheError(f"Failed to import restic snapshot from {repo_path}: {e}")
+    
     def cleanup(self):
         """
         Clean up temporary files created by this instance.
@@ -368,10 +687,8 @@ class UniCache:
     def _file_exists(self, file_id: str) -> bool:
         """Check if a file exists in the cache by trying to get its stats."""
         try:
-            # Try to retrieve to a temporary location to test existence
-            with tempfile.NamedTemporaryFile() as temp_file:
-                self._cache.retrieve_file(file_id, temp_file.name)
-                return True
+            self._cache.get_file_info(file_id)
+            return True
         except:
             return False
     
@@ -141,6 +141,24 @@ def stats(ctx):
         click.echo(f"Deduplication ratio: {dedup_ratio:.2f}x")
         click.echo(f"Space saved: {format_size(logical_size - stored_size)}")
 
+@cli.command()
+@click.argument('target_bytes', type=int)
+@click.option('--dry-run', is_flag=True, help='Only show what would be evicted')
+@click.pass_context
+def evict(ctx, target_bytes, dry_run):
+    """Evict least recently used files to free TARGET_BYTES of storage."""
+    cache = get_cache(ctx.obj['cache_dir'], ctx.obj['block_size'])
+    
+    plan = cache.plan_eviction(target_bytes) if dry_run else cache.evict(target_bytes)
+    
+    for file_id in plan['file_ids']:
+        click.echo(f"  {file_id}")
+    verb = "Would evict" if dry_run else "Evicted"
+    click.echo(f"{verb} {len(plan['file_ids'])} files ({format_size(plan['logical_bytes'])} logical)")
+    click.echo(f"Storage freed: {format_size(plan['freed_bytes'])}")
+    if not plan['target_met']:
+        click.echo(f"Warning: could not free {format_size(target_bytes)}", err=True)
+
 @cli.command()
 @click.argument('url')
 @click.option('--id', help='Custom ID for the downloaded file')